@@ -2,8 +2,16 @@ use std::{net::SocketAddr, str::FromStr};
 
 use axum::{response::IntoResponse, Router};
 
+#[allow(dead_code)]
+#[path="../src/signature.rs"]
+mod signature;
+
 fn main() {
 	let args:Vec<String>=std::env::args().collect();
+	//引数が1つの場合は取得を行わないエンドポイントの応答のみ確認する
+	if args.len()==2{
+		probe(&args[1]);
+	}
 	let bind_port=args.get(1).expect("args[1]=bind_port");
 	let target_url=args.get(2).expect("args[2]=target_url");
 	let http_addr:SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(),bind_port.parse().expect("bind_port parse"));
@@ -42,11 +50,16 @@ fn main() {
 		println!("test server bind error");
 		std::process::exit(1);
 	}
+	//signing_secretが設定されている場合は署名する
+	let mut proxy_url=format!("{}?url={}",target_url,self_url);
+	if let Some(secret)=signing_secret(){
+		proxy_url.push_str(&format!("&sig={}",signature::sign(secret.as_bytes(),self_url.as_str(),&[],None)));
+	}
 	for _ in 0..5{
-		let self_url=self_url.to_string();
+		let proxy_url=proxy_url.clone();
 		let client=client.clone();
 		let status=rt.block_on(async move{
-			if let Ok(s)=client.get(format!("{}?url={}",target_url,self_url)).send().await{
+			if let Ok(s)=client.get(proxy_url).send().await{
 				s.status().as_u16()
 			}else{
 				504
@@ -60,3 +73,26 @@ fn main() {
 	}
 	std::process::exit(2);
 }
+//プロキシと同じく環境変数を設定ファイルより優先する
+fn signing_secret()->Option<String>{
+	if let Ok(secret)=std::env::var("MEDIA_PROXY_SIGNING_SECRET"){
+		if !secret.is_empty(){
+			return Some(secret);
+		}
+	}
+	let path=std::env::var("MEDIA_PROXY_CONFIG_PATH").ok().filter(|p|!p.is_empty()).unwrap_or_else(||"config.json".to_owned());
+	let config:serde_json::Value=serde_json::from_reader(std::fs::File::open(path).ok()?).ok()?;
+	config.get("signing_secret")?.as_str().map(|s|s.to_owned())
+}
+fn probe(url:&str)->!{
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let client=reqwest::Client::builder().timeout(std::time::Duration::from_millis(2000)).build().unwrap();
+	let status=rt.block_on(async{
+		client.get(url).send().await.map(|s|s.status().as_u16()).unwrap_or(504)
+	});
+	if status==200{
+		println!("ok");
+		std::process::exit(0);
+	}
+	std::process::exit(2);
+}
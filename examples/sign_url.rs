@@ -0,0 +1,32 @@
+#[allow(dead_code)]
+#[path="../src/signature.rs"]
+mod signature;
+
+fn main() {
+	let args:Vec<String>=std::env::args().collect();
+	let base_url=args.get(1).expect("args[1]=proxy_url");
+	let url=args.get(2).expect("args[2]=target_url");
+	let secret=std::env::var("MEDIA_PROXY_SIGNING_SECRET").expect("MEDIA_PROXY_SIGNING_SECRET");
+	let mut modes=vec![];
+	let mut expires=None;
+	for arg in args.iter().skip(3){
+		match arg.as_str(){
+			"static"|"emoji"|"avatar"|"preview"|"badge"=>modes.push(arg.as_str()),
+			s=>expires=Some(s.parse::<u64>().expect("args[3..]=mode or expires(unix time)")),
+		}
+	}
+	//署名の検証と同じ順序にする
+	let order=["static","emoji","avatar","preview","badge"];
+	modes.sort_by_key(|m|order.iter().position(|o|o==m));
+	modes.dedup();
+	let mut signed=format!("{}?url={}",base_url,urlencoding::encode(url));
+	for mode in modes.iter(){
+		signed.push_str(&format!("&{}=1",mode));
+	}
+	if let Some(expires)=expires{
+		signed.push_str(&format!("&expires={}",expires));
+	}
+	let sig=signature::sign(secret.as_bytes(),url,&modes,expires);
+	signed.push_str(&format!("&sig={}",sig));
+	println!("{}",signed);
+}
@@ -0,0 +1,252 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use image::DynamicImage;
+use rav1e::prelude::{ChromaSampling, ColorDescription, ColorPrimaries, Config, Context, EncoderConfig, EncoderStatus, FrameType, MatrixCoefficients, PixelRange, Rational, TransferCharacteristics};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="lowercase")]
+pub(crate) enum AvifColorModel{
+	Ycbcr,
+	Rgb,
+}
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub(crate) enum AvifChromaSubsampling{
+	#[serde(rename="444")]
+	Yuv444,
+	#[serde(rename="422")]
+	Yuv422,
+	#[serde(rename="420")]
+	Yuv420,
+}
+//未設定の項目は全体の設定、それも無い場合はimage crateと同じ値を使う
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub(crate) struct AvifOptions{
+	//1(遅い、高圧縮)から10(速い)
+	speed:Option<u8>,
+	//1から100
+	quality:Option<f32>,
+	//未設定の場合はqualityと同じ
+	alpha_quality:Option<f32>,
+	//8か10
+	bit_depth:Option<u8>,
+	color_model:Option<AvifColorModel>,
+	//444(デフォルト)、422、420、color_modelがrgbの場合は常に444
+	chroma_subsampling:Option<AvifChromaSubsampling>,
+	//未設定の場合はrayonの全体のスレッドを使う
+	threads:Option<usize>,
+}
+//threadsごとのスレッドプール、エンコードのたびに作らないよう起動時に用意する
+static THREAD_POOLS:OnceLock<HashMap<usize,rayon::ThreadPool>>=OnceLock::new();
+pub(crate) fn init_thread_pools(base:Option<&AvifOptions>,modes:Option<&HashMap<String,AvifOptions>>){
+	let mut pools=HashMap::new();
+	for threads in base.into_iter().chain(modes.into_iter().flat_map(|m|m.values())).filter_map(|o|o.threads).filter(|n|*n>0){
+		if pools.contains_key(&threads){
+			continue;
+		}
+		match rayon::ThreadPoolBuilder::new().num_threads(threads).thread_name(|i|format!("avif-{}",i)).build(){
+			Ok(pool)=>{
+				pools.insert(threads,pool);
+			},
+			Err(e)=>println!("avif thread pool {} {:?}",threads,e),
+		}
+	}
+	let _=THREAD_POOLS.set(pools);
+}
+impl AvifOptions{
+	//モード別の設定が無い項目は全体の設定を使う
+	pub fn for_mode(base:Option<&AvifOptions>,modes:Option<&HashMap<String,AvifOptions>>,mode:Option<&str>)->Self{
+		let base=base.cloned().unwrap_or_default();
+		let over=match mode.and_then(|mode|modes?.get(mode)){
+			Some(over)=>over,
+			None=>return base,
+		};
+		Self{
+			speed:over.speed.or(base.speed),
+			quality:over.quality.or(base.quality),
+			alpha_quality:over.alpha_quality.or(base.alpha_quality),
+			bit_depth:over.bit_depth.or(base.bit_depth),
+			color_model:over.color_model.or(base.color_model),
+			chroma_subsampling:over.chroma_subsampling.or(base.chroma_subsampling),
+			threads:over.threads.or(base.threads),
+		}
+	}
+	//ravifは範囲外の値でpanicするので丸める
+	fn speed(&self)->u8{
+		self.speed.unwrap_or(4).clamp(1,10)
+	}
+	fn quality(&self)->f32{
+		self.quality.unwrap_or(80f32).clamp(1f32,100f32)
+	}
+	fn alpha_quality(&self)->f32{
+		self.alpha_quality.unwrap_or(self.quality()).clamp(1f32,100f32)
+	}
+	fn encoder(&self)->ravif::Encoder<'static>{
+		ravif::Encoder::new()
+			.with_speed(self.speed())
+			.with_quality(self.quality())
+			.with_alpha_quality(self.alpha_quality())
+			.with_bit_depth(match self.bit_depth{
+				Some(10)=>ravif::BitDepth::Ten,
+				_=>ravif::BitDepth::Eight,
+			})
+			.with_internal_color_model(match self.color_model{
+				Some(AvifColorModel::Rgb)=>ravif::ColorModel::RGB,
+				_=>ravif::ColorModel::YCbCr,
+			})
+			//スレッド数はエンコードを実行するスレッドプールで決まる
+			.with_num_threads(None)
+	}
+	pub fn encode(&self,img:&DynamicImage)->Result<Vec<u8>,String>{
+		let rgba=img.to_rgba8();
+		let encode=||match self.chroma_subsampling{
+			//ravifは4:4:4のみなのでサブサンプリングする場合はrav1eで直接エンコードする
+			Some(subsampling@(AvifChromaSubsampling::Yuv422|AvifChromaSubsampling::Yuv420)) if self.color_model!=Some(AvifColorModel::Rgb)=>{
+				self.encode_subsampled(&rgba,subsampling)
+			},
+			_=>{
+				let pixels:Vec<ravif::RGBA8>=rgba.pixels().map(|p|ravif::RGBA8::new(p[0],p[1],p[2],p[3])).collect();
+				let buffer=ravif::Img::new(pixels.as_slice(),rgba.width() as usize,rgba.height() as usize);
+				//全て不透明な場合はアルファチャンネルを省く
+				self.encoder().encode_rgba(buffer).map(|encoded|encoded.avif_file).map_err(|e|format!("{:?}",e))
+			},
+		};
+		match self.threads.and_then(|n|THREAD_POOLS.get()?.get(&n)){
+			Some(pool)=>pool.install(encode),
+			None=>encode(),
+		}
+	}
+	//ravifと同じBT.601のフルレンジのYCbCrに変換して色差を縮小する
+	fn encode_subsampled(&self,rgba:&image::RgbaImage,subsampling:AvifChromaSubsampling)->Result<Vec<u8>,String>{
+		let (width,height)=(rgba.width() as usize,rgba.height() as usize);
+		let (xdec,ydec)=match subsampling{
+			AvifChromaSubsampling::Yuv420=>(1,1),
+			_=>(1,0),
+		};
+		let (chroma_width,chroma_height)=((width+xdec)>>xdec,(height+ydec)>>ydec);
+		let depth=if self.bit_depth==Some(10){10}else{8};
+		let max=((1<<depth)-1) as f32;
+		let scale=max/255f32;
+		let shift=(max*0.5).round();
+		let mut luma=Vec::with_capacity(width*height);
+		//Cb、Cr、画素数
+		let mut chroma=vec![[0f32;3];chroma_width*chroma_height];
+		for (x,y,p) in rgba.enumerate_pixels(){
+			let (r,g,b)=(p[0] as f32*scale,p[1] as f32*scale,p[2] as f32*scale);
+			let l=0.299*r+0.587*g+0.114*b;
+			luma.push(l.round().clamp(0f32,max) as u16);
+			let c=&mut chroma[(y as usize>>ydec)*chroma_width+(x as usize>>xdec)];
+			c[0]+=(b-l)*(0.5/(1f32-0.114))+shift;
+			c[1]+=(r-l)*(0.5/(1f32-0.299))+shift;
+			c[2]+=1f32;
+		}
+		let cb:Vec<u16>=chroma.iter().map(|c|(c[0]/c[2]).round().clamp(0f32,max) as u16).collect();
+		let cr:Vec<u16>=chroma.iter().map(|c|(c[1]/c[2]).round().clamp(0f32,max) as u16).collect();
+		let color=self.encode_av1(width,height,depth,self.quality(),match subsampling{
+			AvifChromaSubsampling::Yuv420=>ChromaSampling::Cs420,
+			_=>ChromaSampling::Cs422,
+		},&[(&luma,width),(&cb,chroma_width),(&cr,chroma_width)])?;
+		//全て不透明な場合はアルファチャンネルを省く
+		let alpha=if rgba.pixels().any(|p|p[3]!=255){
+			let alpha:Vec<u16>=rgba.pixels().map(|p|if depth==10{(p[3] as u16)<<2|(p[3] as u16)>>6}else{p[3] as u16}).collect();
+			Some(self.encode_av1(width,height,depth,self.alpha_quality(),ChromaSampling::Cs400,&[(&alpha,width)])?)
+		}else{
+			None
+		};
+		Ok(avif_serialize::Aviffy::new()
+			.set_chroma_subsampling((true,ydec==1))
+			//4:2:2はProfessional、4:2:0はMain
+			.set_seq_profile(if ydec==1{0}else{2})
+			.to_vec(&color,alpha.as_deref(),width as u32,height as u32,depth as u8))
+	}
+	fn encode_av1(&self,width:usize,height:usize,depth:usize,quality:f32,chroma_sampling:ChromaSampling,planes:&[(&[u16],usize)])->Result<Vec<u8>,String>{
+		if depth==10{
+			self.encode_av1_pixel::<u16>(width,height,depth,quality,chroma_sampling,planes)
+		}else{
+			self.encode_av1_pixel::<u8>(width,height,depth,quality,chroma_sampling,planes)
+		}
+	}
+	fn encode_av1_pixel<P:rav1e::Pixel>(&self,width:usize,height:usize,depth:usize,quality:f32,chroma_sampling:ChromaSampling,planes:&[(&[u16],usize)])->Result<Vec<u8>,String>{
+		let mut config=EncoderConfig::with_speed_preset(self.speed());
+		config.width=width;
+		config.height=height;
+		config.bit_depth=depth;
+		config.chroma_sampling=chroma_sampling;
+		config.pixel_range=PixelRange::Full;
+		if chroma_sampling!=ChromaSampling::Cs400{
+			config.color_description=Some(ColorDescription{
+				color_primaries:ColorPrimaries::BT709,
+				transfer_characteristics:TransferCharacteristics::SRGB,
+				matrix_coefficients:MatrixCoefficients::BT601,
+			});
+		}
+		config.time_base=Rational::new(1,1);
+		config.still_picture=true;
+		let quantizer=quality_to_quantizer(quality);
+		config.quantizer=quantizer as usize;
+		config.min_quantizer=quantizer;
+		//スレッド数はエンコードを実行するスレッドプールで決まる
+		let mut ctx:Context<P>=Config::new().with_encoder_config(config).new_context().map_err(|e|format!("{:?}",e))?;
+		let mut frame=ctx.new_frame();
+		for (plane,(data,plane_width)) in frame.planes.iter_mut().zip(planes){
+			for (row,src) in plane.mut_slice(Default::default()).rows_iter_mut().zip(data.chunks(*plane_width)){
+				for (dst,src) in row.iter_mut().zip(src){
+					*dst=P::cast_from(*src);
+				}
+			}
+		}
+		ctx.send_frame(frame).map_err(|e|format!("{:?}",e))?;
+		ctx.flush();
+		let mut out=vec![];
+		loop{
+			match ctx.receive_packet(){
+				Ok(mut packet)=>if packet.frame_type==FrameType::KEY{
+					out.append(&mut packet.data);
+				},
+				Err(EncoderStatus::Encoded|EncoderStatus::LimitReached)=>break,
+				Err(e)=>return Err(format!("{:?}",e)),
+			}
+		}
+		Ok(out)
+	}
+}
+//ravifと同じ品質から量子化パラメータへの変換
+fn quality_to_quantizer(quality:f32)->u8{
+	let q=quality/100f32;
+	let x=if q>=0.82{
+		(1f32-q)*2.6
+	}else if q>0.25{
+		1f32-0.125-q*0.5
+	}else{
+		1f32-q
+	};
+	(x*255f32).round() as u8
+}
+#[test]
+fn avif_options(){
+	let base:AvifOptions=serde_json::from_str(r#"{"speed":4,"quality":70,"threads":2}"#).unwrap();
+	let modes:HashMap<String,AvifOptions>=serde_json::from_str(r#"{"emoji":{"speed":10,"alpha_quality":90}}"#).unwrap();
+	let emoji=AvifOptions::for_mode(Some(&base),Some(&modes),Some("emoji"));
+	assert_eq!((emoji.speed,emoji.quality,emoji.alpha_quality,emoji.threads),(Some(10),Some(70f32),Some(90f32),Some(2)));
+	assert_eq!(AvifOptions::for_mode(Some(&base),Some(&modes),Some("avatar")),base);
+	assert_eq!(AvifOptions::for_mode(None,None,None),AvifOptions::default());
+	//範囲外の値でもpanicしない
+	let options=AvifOptions{speed:Some(0),quality:Some(0f32),alpha_quality:Some(200f32),bit_depth:Some(10),color_model:Some(AvifColorModel::Rgb),chroma_subsampling:Some(AvifChromaSubsampling::Yuv420),threads:Some(0)};
+	let img=DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(8,8,image::Rgba([255,0,0,128])));
+	let avif=options.encode(&img).unwrap();
+	assert_eq!(image::guess_format(&avif).unwrap(),image::ImageFormat::Avif);
+	//av1Cのchroma_subsampling_xとchroma_subsampling_y、RGBは4:4:4のまま
+	let subsampling=|avif:&[u8]|avif.windows(4).position(|w|w==b"av1C").map(|i|(avif[i+6]>>2)&3);
+	assert_eq!(subsampling(&avif),Some(0));
+	let modes:HashMap<String,AvifOptions>=serde_json::from_str(r#"{"avatar":{"chroma_subsampling":"420"},"emoji":{"chroma_subsampling":"422","bit_depth":10}}"#).unwrap();
+	let odd=DynamicImage::ImageRgba8(image::RgbaImage::from_fn(9,7,|x,y|image::Rgba([(x*28) as u8,(y*36) as u8,128,if x==0{0}else{255}])));
+	for (mode,expected) in [("avatar",3),("emoji",2)]{
+		let avif=AvifOptions::for_mode(Some(&base),Some(&modes),Some(mode)).encode(&odd).unwrap();
+		assert_eq!(image::guess_format(&avif).unwrap(),image::ImageFormat::Avif);
+		assert_eq!(subsampling(&avif),Some(expected));
+	}
+	//起動時に作ったスレッドプールで実行する
+	init_thread_pools(Some(&base),Some(&modes));
+	assert_eq!(THREAD_POOLS.get().unwrap().get(&2).map(|p|p.current_num_threads()),Some(2));
+	assert!(base.encode(&img).is_ok());
+}
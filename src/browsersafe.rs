@@ -29,3 +29,85 @@ pub const FILE_TYPE_BROWSERSAFE: [&str; 20] = [
 	"audio/x-flac",
 	"audio/vnd.wave",
 ];
+//宣言されたContent-Typeと先頭のバイト列が一致するか、判定できない形式はNone
+pub fn sniff_matches(content_type:&str,head:&[u8])->Option<bool>{
+	let signatures:&[fn(&[u8])->bool]=match content_type{
+		"audio/opus"|"video/ogg"|"audio/ogg"|"application/ogg"=>&[is_ogg],
+		"video/quicktime"|"video/mp4"|"audio/mp4"|"video/x-m4v"|"audio/x-m4a"|"video/3gpp"|"video/3gpp2"=>&[is_isobmff],
+		"video/mpeg"=>&[is_mpeg_video],
+		"audio/mpeg"=>&[is_mp3],
+		"video/webm"|"audio/webm"=>&[is_ebml],
+		"audio/aac"=>&[is_aac],
+		"audio/flac"|"audio/x-flac"=>&[is_flac],
+		"audio/wav"|"audio/vnd.wave"=>&[is_wav],
+		_=>return None,
+	};
+	Some(signatures.iter().any(|f|f(head)))
+}
+fn is_ogg(head:&[u8])->bool{
+	head.starts_with(b"OggS")
+}
+fn is_isobmff(head:&[u8])->bool{
+	//古いQuickTimeはftypが無い場合がある
+	matches!(head.get(4..8),Some(b"ftyp"|b"moov"|b"mdat"|b"free"|b"skip"|b"wide"|b"pnot"))
+}
+fn is_ebml(head:&[u8])->bool{
+	head.starts_with(&[0x1A,0x45,0xDF,0xA3])
+}
+fn is_mpeg_video(head:&[u8])->bool{
+	//Program Stream、Elementary Stream、Transport Stream
+	head.starts_with(&[0x00,0x00,0x01,0xBA])||head.starts_with(&[0x00,0x00,0x01,0xB3])||(head.first()==Some(&0x47)&&head.get(188)==Some(&0x47))
+}
+//ID3タグを読み飛ばした後の部分、タグが先頭部分に収まらない場合はErr
+//その場合は後続を確認できないので一致しないものとして扱う
+fn skip_id3(head:&[u8])->Result<&[u8],()>{
+	if !head.starts_with(b"ID3")||head.len()<10{
+		return Ok(head);
+	}
+	let size=head[6..10].iter().fold(0usize,|size,b|(size<<7)|(*b&0x7F) as usize);
+	let footer=if head[5]&0x10!=0{10}else{0};
+	head.get(10+size+footer..).ok_or(())
+}
+fn is_mp3(head:&[u8])->bool{
+	match skip_id3(head){
+		Ok(head)=>head.len()>=2&&head[0]==0xFF&&head[1]&0xE0==0xE0&&head[1]&0x06!=0,
+		Err(_)=>false,
+	}
+}
+fn is_aac(head:&[u8])->bool{
+	match skip_id3(head){
+		Ok(head)=>head.starts_with(b"ADIF")||(head.len()>=2&&head[0]==0xFF&&head[1]&0xF6==0xF0),
+		Err(_)=>false,
+	}
+}
+fn is_flac(head:&[u8])->bool{
+	match skip_id3(head){
+		Ok(head)=>head.starts_with(b"fLaC"),
+		Err(_)=>false,
+	}
+}
+fn is_wav(head:&[u8])->bool{
+	head.starts_with(b"RIFF")&&head.get(8..12)==Some(b"WAVE")
+}
+#[test]
+fn sniff_media(){
+	assert_eq!(sniff_matches("video/mp4",b"\0\0\0\x20ftypisom\0\0\x02\0"),Some(true));
+	assert_eq!(sniff_matches("video/mp4",b"<!DOCTYPE html><script>"),Some(false));
+	assert_eq!(sniff_matches("audio/ogg",b"OggS\0\x02"),Some(true));
+	assert_eq!(sniff_matches("audio/ogg",b"\x1A\x45\xDF\xA3"),Some(false));
+	assert_eq!(sniff_matches("video/webm",b"\x1A\x45\xDF\xA3\x9F"),Some(true));
+	assert_eq!(sniff_matches("audio/wav",b"RIFF\x24\0\0\0WAVEfmt "),Some(true));
+	assert_eq!(sniff_matches("audio/wav",b"RIFF\x24\0\0\0WEBPVP8 "),Some(false));
+	assert_eq!(sniff_matches("audio/mpeg",b"\xFF\xFB\x90\x64"),Some(true));
+	assert_eq!(sniff_matches("audio/mpeg",b"ID3\x04\0\0\0\0\0\x02\0\0\xFF\xFB"),Some(true));
+	assert_eq!(sniff_matches("audio/mpeg",b"ID3\x04\0\0\0\0\0\x02\0\0<h"),Some(false));
+	//sniff_windowに収まらないID3タグ
+	assert_eq!(sniff_matches("audio/mpeg",b"ID3\x04\0\0\0\0\x7F\x7F<html>"),Some(false));
+	assert_eq!(sniff_matches("audio/aac",b"ID3\x04\0\0\0\0\x7F\x7F<html>"),Some(false));
+	assert_eq!(sniff_matches("audio/flac",b"ID3\x04\0\0\0\0\x7F\x7F<html>"),Some(false));
+	assert_eq!(sniff_matches("audio/aac",b"\xFF\xF1\x50\x80"),Some(true));
+	assert_eq!(sniff_matches("audio/aac",b"\xFF\xFB\x90\x64"),Some(false));
+	assert_eq!(sniff_matches("audio/flac",b"fLaC\0\0\0\x22"),Some(true));
+	assert_eq!(sniff_matches("video/mpeg",b"\0\0\x01\xBA\x44"),Some(true));
+	assert_eq!(sniff_matches("text/html",b"<html>"),None);
+}
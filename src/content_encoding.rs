@@ -0,0 +1,147 @@
+use std::pin::Pin;
+
+use axum::body::Bytes;
+use axum::http::HeaderValue;
+use futures::Stream;
+use tokio_stream::StreamExt;
+
+//変換する本文のために取得先へ送るAccept-Encoding
+pub(crate) const ACCEPT_ENCODING:&str="gzip, br, zstd";
+
+pub(crate) type ByteStream=Pin<Box<dyn Stream<Item=std::io::Result<Bytes>>+Send+Sync>>;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum ContentEncoding{
+	Gzip,
+	Brotli,
+	Zstd,
+}
+impl ContentEncoding{
+	//未設定やidentityはNone、展開できないものはErr
+	pub fn from_header(value:Option<&HeaderValue>)->Result<Option<Self>,String>{
+		let value=match value{
+			Some(value)=>String::from_utf8_lossy(value.as_bytes()).trim().to_lowercase(),
+			None=>return Ok(None),
+		};
+		match value.as_str(){
+			""|"identity"=>Ok(None),
+			"gzip"|"x-gzip"=>Ok(Some(Self::Gzip)),
+			"br"=>Ok(Some(Self::Brotli)),
+			"zstd"=>Ok(Some(Self::Zstd)),
+			_=>Err(format!("ContentEncoding:{}",value)),
+		}
+	}
+	//クライアントのAccept-Encodingがこの形式を受け付けるか
+	pub fn is_accepted(&self,accept_encoding:Option<&str>)->bool{
+		let names:&[&str]=match self{
+			Self::Gzip=>&["gzip","x-gzip"],
+			Self::Brotli=>&["br"],
+			Self::Zstd=>&["zstd"],
+		};
+		let mut wildcard=None;
+		for e in accept_encoding.unwrap_or_default().split(','){
+			let mut params=e.split(';');
+			let coding=params.next().unwrap_or_default().trim().to_ascii_lowercase();
+			let q=params.filter_map(|p|p.split_once('=')).find(|(k,_)|k.trim().eq_ignore_ascii_case("q")).map(|(_,v)|v.trim().parse::<f32>().unwrap_or(0f32)).unwrap_or(1f32);
+			if names.contains(&coding.as_str()){
+				return q>0f32;
+			}
+			if coding=="*"{
+				wildcard=Some(q>0f32);
+			}
+		}
+		wildcard.unwrap_or(false)
+	}
+	pub fn decode<S>(self,stream:S)->ByteStream where S:Stream<Item=std::io::Result<Bytes>>+Send+Sync+'static{
+		use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder};
+		let reader=tokio_util::io::StreamReader::new(stream);
+		match self{
+			Self::Gzip=>Box::pin(tokio_util::io::ReaderStream::new(GzipDecoder::new(reader))),
+			Self::Brotli=>Box::pin(tokio_util::io::ReaderStream::new(BrotliDecoder::new(reader))),
+			Self::Zstd=>Box::pin(tokio_util::io::ReaderStream::new(ZstdDecoder::new(reader))),
+		}
+	}
+	//形式の判定に使うため途中までのデータを展開できるところまで展開する
+	pub async fn decode_head(self,head:Bytes,limit:usize)->Bytes{
+		let mut stream=self.decode(futures::stream::iter([Ok(head)]));
+		let mut decoded=Vec::new();
+		while decoded.len()<limit{
+			match stream.next().await{
+				Some(Ok(b))=>decoded.extend_from_slice(&b),
+				_=>break,
+			}
+		}
+		decoded.into()
+	}
+}
+#[test]
+fn content_encoding_stand_in(){
+	use std::io::{BufRead, BufReader, Write};
+	use tokio::io::AsyncWriteExt;
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let body=b"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"1\" height=\"1\"/>".repeat(100);
+	let compressed:Vec<(&str,Vec<u8>)>=rt.block_on(async{
+		use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+		let mut gzip=GzipEncoder::new(Vec::new());
+		gzip.write_all(&body).await.unwrap();
+		gzip.shutdown().await.unwrap();
+		let mut br=BrotliEncoder::new(Vec::new());
+		br.write_all(&body).await.unwrap();
+		br.shutdown().await.unwrap();
+		let mut zstd=ZstdEncoder::new(Vec::new());
+		zstd.write_all(&body).await.unwrap();
+		zstd.shutdown().await.unwrap();
+		vec![("gzip",gzip.into_inner()),("br",br.into_inner()),("zstd",zstd.into_inner())]
+	});
+	//パスに応じて圧縮済みの本文を返すスタブ
+	let listener=std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr=listener.local_addr().unwrap();
+	let served=compressed.clone();
+	std::thread::spawn(move||{
+		for stream in listener.incoming(){
+			let mut stream=match stream{
+				Ok(stream)=>stream,
+				Err(_)=>break,
+			};
+			let mut line=String::new();
+			let mut reader=BufReader::new(&stream);
+			reader.read_line(&mut line).unwrap();
+			loop{
+				let mut header=String::new();
+				if reader.read_line(&mut header).unwrap()==0||header=="\r\n"{
+					break;
+				}
+			}
+			let path=line.split(' ').nth(1).unwrap_or_default().trim_start_matches('/').to_owned();
+			let (encoding,body)=served.iter().find(|(e,_)|*e==path).unwrap();
+			write!(stream,"HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\nContent-Encoding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",encoding,body.len()).unwrap();
+			stream.write_all(body).unwrap();
+		}
+	});
+	rt.block_on(async{
+		let client=reqwest::Client::new();
+		for (path,raw) in compressed.iter(){
+			let resp=client.get(format!("http://{}/{}",addr,path)).header("Accept-Encoding",ACCEPT_ENCODING).send().await.unwrap();
+			let encoding=ContentEncoding::from_header(resp.headers().get("Content-Encoding")).unwrap().unwrap();
+			let mut stream=encoding.decode(resp.bytes_stream().map(|r|r.map_err(std::io::Error::other)));
+			let mut decoded=Vec::new();
+			while let Some(b)=stream.next().await{
+				decoded.extend_from_slice(&b.unwrap());
+			}
+			assert_eq!(decoded,body);
+			let head=encoding.decode_head(Bytes::copy_from_slice(raw),16).await;
+			assert!(head.starts_with(b"<svg"));
+		}
+		//途中までのデータからも先頭部分を取り出せる
+		let (_,gzip)=&compressed[0];
+		let head=ContentEncoding::Gzip.decode_head(Bytes::copy_from_slice(&gzip[..gzip.len()/2]),16).await;
+		assert!(head.starts_with(b"<svg"));
+	});
+	assert_eq!(ContentEncoding::from_header(Some(&HeaderValue::from_static("identity"))),Ok(None));
+	assert!(ContentEncoding::from_header(Some(&HeaderValue::from_static("gzip, br"))).is_err());
+	assert!(ContentEncoding::Gzip.is_accepted(Some("gzip")));
+	assert!(!ContentEncoding::Brotli.is_accepted(Some("gzip")));
+	assert!(!ContentEncoding::Brotli.is_accepted(None));
+	assert!(ContentEncoding::Zstd.is_accepted(Some("gzip, *;q=0.5")));
+	assert!(!ContentEncoding::Zstd.is_accepted(Some("zstd;q=0, *")));
+}
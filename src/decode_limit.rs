@@ -0,0 +1,145 @@
+use image::{DynamicImage, ImageError};
+
+use crate::ConfigFile;
+
+//デコード前にヘッダのサイズを検査して展開後のメモリ量を制限する
+#[derive(Clone)]
+pub(crate) struct DecodeLimits{
+	max_width:u32,
+	max_height:u32,
+	max_pixels:u64,
+	max_anim_pixels:u64,
+}
+impl DecodeLimits{
+	pub fn new(config:&ConfigFile)->Self{
+		let max_pixels=config.max_decode_pixels.unwrap_or(8192*8192);
+		Self{
+			max_width:config.max_decode_width.unwrap_or(16384),
+			max_height:config.max_decode_height.unwrap_or(16384),
+			max_pixels,
+			max_anim_pixels:config.max_anim_pixels.unwrap_or(max_pixels*2),
+		}
+	}
+	pub fn max_pixels(&self)->u64{
+		self.max_pixels
+	}
+	pub fn check(&self,width:u32,height:u32)->Result<(),String>{
+		if width>self.max_width||height>self.max_height{
+			return Err(format!("DecodeLimit {}x{}>{}x{}",width,height,self.max_width,self.max_height));
+		}
+		let pixels=width as u64*height as u64;
+		if pixels>self.max_pixels{
+			return Err(format!("DecodeLimit pixels:{}>{}",pixels,self.max_pixels));
+		}
+		Ok(())
+	}
+	pub fn check_anim(&self,pixels:u64)->Result<(),String>{
+		if pixels>self.max_anim_pixels{
+			return Err(format!("DecodeLimit animPixels:{}>{}",pixels,self.max_anim_pixels));
+		}
+		Ok(())
+	}
+	//check_animで拒否される分のフレームは見積もりに含めない
+	pub fn anim_frames_hint(&self,pixels:u64,frames:u64)->u64{
+		frames.min(self.max_anim_pixels/pixels.max(1)).max(1)
+	}
+	pub fn image_limits(&self)->image::Limits{
+		let mut limits=image::Limits::default();
+		limits.max_image_width=Some(self.max_width);
+		limits.max_image_height=Some(self.max_height);
+		//16bit RGBAでも収まる量
+		limits.max_alloc=Some(self.max_pixels*8);
+		limits
+	}
+	pub fn load_from_memory(&self,bytes:&[u8],format:image::ImageFormat)->Result<DynamicImage,String>{
+		let reader=image::ImageReader::with_format(std::io::Cursor::new(bytes),format);
+		let (width,height)=reader.into_dimensions().map_err(error_code)?;
+		self.check(width,height)?;
+		let mut reader=image::ImageReader::with_format(std::io::Cursor::new(bytes),format);
+		reader.limits(self.image_limits());
+		reader.decode().map_err(error_code)
+	}
+}
+pub(crate) fn error_code(e:ImageError)->String{
+	match e{
+		ImageError::Limits(e)=>format!("DecodeLimit {}",e),
+		e=>format!("DecodeError_{:?}",e),
+	}
+}
+fn be32(bytes:&[u8],offset:usize)->Option<u32>{
+	Some(u32::from_be_bytes(bytes.get(offset..offset+4)?.try_into().ok()?))
+}
+fn find_box<'a>(mut data:&'a [u8],name:&[u8])->Option<&'a [u8]>{
+	while data.len()>=8{
+		let (header,len)=match be32(data,0)?{
+			0=>(8,data.len()),
+			1=>(16,u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize),
+			len=>(8,len as usize),
+		};
+		if len<header||len>data.len(){
+			return None;
+		}
+		if &data[4..8]==name{
+			return Some(&data[header..len]);
+		}
+		data=&data[len..];
+	}
+	None
+}
+//JPEG 2000の画像サイズ(コードストリームのSIZマーカーまたはJP2のihdrボックス)
+pub(crate) fn probe_jp2(bytes:&[u8])->Option<(u32,u32)>{
+	if bytes.starts_with(&[0xFF,0x4F,0xFF,0x51]){
+		let width=be32(bytes,8)?.checked_sub(be32(bytes,16)?)?;
+		let height=be32(bytes,12)?.checked_sub(be32(bytes,20)?)?;
+		return Some((width,height));
+	}
+	let ihdr=find_box(find_box(bytes,b"jp2h")?,b"ihdr")?;
+	Some((be32(ihdr,4)?,be32(ihdr,0)?))
+}
+//アニメーションWebPのフレーム数(ANMFチャンクの数)
+pub(crate) fn webp_frames(bytes:&[u8])->u64{
+	let mut data=bytes.get(12..).unwrap_or_default();
+	let mut frames=0;
+	while data.len()>=8{
+		if &data[0..4]==b"ANMF"{
+			frames+=1;
+		}
+		let len=u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+		let next=8+len+(len&1);
+		if next>data.len(){
+			break;
+		}
+		data=&data[next..];
+	}
+	frames
+}
+#[test]
+fn decode_limit_header(){
+	let limits=DecodeLimits{
+		max_width:1000,
+		max_height:1000,
+		max_pixels:500*500,
+		max_anim_pixels:500*500*2,
+	};
+	//画素データを持たない60000x60000のfarbfeld
+	let mut bomb=b"farbfeld".to_vec();
+	bomb.extend_from_slice(&60000u32.to_be_bytes());
+	bomb.extend_from_slice(&60000u32.to_be_bytes());
+	assert!(limits.load_from_memory(&bomb,image::ImageFormat::Farbfeld).unwrap_err().starts_with("DecodeLimit"));
+	assert!(limits.check(600,600).is_err());
+	assert!(limits.check(500,500).is_ok());
+	assert!(limits.check_anim(500*500*3).is_err());
+	assert_eq!(limits.anim_frames_hint(500*500,1_000_000),2);
+	assert_eq!(limits.anim_frames_hint(100*100,3),3);
+	assert_eq!(limits.anim_frames_hint(1000*1000,0),1);
+	let mut j2k=vec![0xFF,0x4F,0xFF,0x51,0,41,0,0];
+	for v in [60000u32,40000,0,0]{
+		j2k.extend_from_slice(&v.to_be_bytes());
+	}
+	assert_eq!(probe_jp2(&j2k),Some((60000,40000)));
+	let mut jp2=vec![0,0,0,12,b'j',b'P',b' ',b' ',0x0D,0x0A,0x87,0x0A,0,0,0,30,b'j',b'p',b'2',b'h',0,0,0,22,b'i',b'h',b'd',b'r'];
+	jp2.extend_from_slice(&40000u32.to_be_bytes());
+	jp2.extend_from_slice(&60000u32.to_be_bytes());
+	jp2.extend_from_slice(&[0,3,7,7,0,0]);
+	assert_eq!(probe_jp2(&jp2),Some((60000,40000)));
+}
@@ -0,0 +1,179 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, sync::{Arc, Mutex}};
+
+use resvg::usvg::{self, fontdb};
+
+use crate::ConfigFile;
+
+//asset/font/Aileron-Light.otf
+const BUNDLED_FAMILY:&str="Aileron";
+//SVGの文字に使うフォントと文字の種類ごとの代替フォント
+pub(crate) struct Fonts{
+	db:Arc<fontdb::Database>,
+	default_family:String,
+	fallbacks:HashMap<String,Vec<String>>,
+	//代替フォントが持つ文字、フォントごとに一度だけ読み取る
+	coverage:Mutex<HashMap<fontdb::ID,Arc<HashSet<u32>>>>,
+}
+impl Fonts{
+	pub fn load(config:&ConfigFile)->Self{
+		let mut db=fontdb::Database::new();
+		if config.load_system_fonts{
+			db.load_system_fonts();
+		}
+		let dirs=config.font_dirs.clone().unwrap_or_else(||vec!["asset/font/".to_owned()]);
+		for dir in dirs.iter(){
+			if std::path::Path::new(dir).exists(){
+				db.load_fonts_dir(dir);
+			}else{
+				println!("font dir not found {}",dir);
+			}
+		}
+		db.load_font_source(fontdb::Source::Binary(Arc::new(include_bytes!("../asset/font/Aileron-Light.otf"))));
+		let fonts=Self::new(db,config);
+		fonts.report();
+		fonts
+	}
+	pub fn new(db:fontdb::Database,config:&ConfigFile)->Self{
+		let mut fallbacks:HashMap<String,Vec<String>>=[
+			("ja",&["Noto Sans CJK JP","Noto Sans JP","Source Han Sans JP","IPAexGothic","IPAGothic","Hiragino Sans","Yu Gothic","Meiryo"][..]),
+			("zh",&["Noto Sans CJK SC","Noto Sans SC","Source Han Sans SC","Noto Sans CJK TC","Microsoft YaHei"][..]),
+			("ko",&["Noto Sans CJK KR","Noto Sans KR","Source Han Sans KR","Malgun Gothic"][..]),
+			("emoji",&["Noto Color Emoji","Twemoji Mozilla","Apple Color Emoji","Segoe UI Emoji"][..]),
+		].into_iter().map(|(k,v)|(k.to_owned(),v.iter().map(|s|s.to_string()).collect())).collect();
+		for (k,v) in config.font_fallbacks.iter().flatten(){
+			fallbacks.insert(k.clone(),v.clone());
+		}
+		//未設定の場合は日本語の代替フォントのうち読み込まれているもの、無ければ同梱のフォントを使う
+		let default_family=match config.font_family.clone(){
+			Some(family)=>family,
+			None=>{
+				let family=fallbacks.get("ja").into_iter().flatten().find(|family|{
+					db.faces().any(|f|f.families.iter().any(|(name,_)|name==*family))
+				}).cloned().unwrap_or_else(||BUNDLED_FAMILY.to_owned());
+				println!("font_family not set, using {}",family);
+				family
+			}
+		};
+		Self{
+			db:Arc::new(db),
+			default_family,
+			fallbacks,
+			coverage:Mutex::new(HashMap::new()),
+		}
+	}
+	pub fn db(&self)->Arc<fontdb::Database>{
+		self.db.clone()
+	}
+	pub fn default_family(&self)->&str{
+		&self.default_family
+	}
+	//読み込んだフォントと使える代替フォントを起動時に出力する
+	fn report(&self){
+		let families:BTreeSet<&str>=self.db.faces().filter_map(|f|f.families.first().map(|(name,_)|name.as_str())).collect();
+		println!("fonts faces:{} default:{}",self.db.len(),self.default_family);
+		println!("font families {}",families.iter().copied().collect::<Vec<_>>().join(","));
+		let mut keys:Vec<&String>=self.fallbacks.keys().collect();
+		keys.sort();
+		for key in keys{
+			let found:Vec<&str>=self.fallbacks[key].iter().filter(|family|families.contains(family.as_str())).map(|s|s.as_str()).collect();
+			if found.is_empty(){
+				println!("font fallback {} not found",key);
+			}else{
+				println!("font fallback {} {}",key,found.join(","));
+			}
+		}
+	}
+	//文字の種類ごとの代替フォントを優先順に並べる
+	fn fallback_families(&self,c:char)->impl Iterator<Item=&String>{
+		scripts(c).iter().chain(["default"].iter()).filter_map(|key|self.fallbacks.get(*key)).flatten()
+	}
+	pub fn font_resolver(self:&Arc<Self>)->usvg::FontResolver<'static>{
+		let fonts=self.clone();
+		let default_fallback=usvg::FontResolver::default_fallback_selector();
+		usvg::FontResolver{
+			select_font:usvg::FontResolver::default_font_selector(),
+			select_fallback:Box::new(move|c,exclude,db|{
+				//元のフォントに近い太さと形のものを選ぶ
+				let base=exclude.first().and_then(|id|db.face(*id));
+				for family in fonts.fallback_families(c){
+					let query=fontdb::Query{
+						families:&[fontdb::Family::Name(family)],
+						weight:base.map(|f|f.weight).unwrap_or_default(),
+						stretch:base.map(|f|f.stretch).unwrap_or_default(),
+						style:base.map(|f|f.style).unwrap_or_default(),
+					};
+					if let Some(id)=db.query(&query){
+						if !exclude.contains(&id)&&fonts.has_char(db,id,c){
+							return Some(id);
+						}
+					}
+				}
+				default_fallback(c,exclude,db)
+			}),
+		}
+	}
+	fn has_char(&self,db:&fontdb::Database,id:fontdb::ID,c:char)->bool{
+		let coverage=self.coverage.lock().unwrap().get(&id).cloned();
+		let coverage=match coverage{
+			Some(coverage)=>coverage,
+			None=>{
+				let coverage=Arc::new(face_coverage(db,id));
+				self.coverage.lock().unwrap().insert(id,coverage.clone());
+				coverage
+			}
+		};
+		coverage.contains(&(c as u32))
+	}
+}
+fn face_coverage(db:&fontdb::Database,id:fontdb::ID)->HashSet<u32>{
+	db.with_face_data(id,|data,index|{
+		let mut coverage=HashSet::new();
+		if let Ok(face)=ttf_parser::Face::parse(data,index){
+			for subtable in face.tables().cmap.iter().flat_map(|cmap|cmap.subtables){
+				if subtable.is_unicode(){
+					subtable.codepoints(|c|{
+						if char::from_u32(c).and_then(|c|face.glyph_index(c)).is_some(){
+							coverage.insert(c);
+						}
+					});
+				}
+			}
+		}
+		coverage
+	}).unwrap_or_default()
+}
+//漢字は日本語のフォントを優先する
+fn scripts(c:char)->&'static [&'static str]{
+	match c as u32{
+		0x3040..=0x30FF|0x31F0..=0x31FF|0xFF66..=0xFF9F=>&["ja"],
+		0x1100..=0x11FF|0x3130..=0x318F|0xAC00..=0xD7AF=>&["ko","ja"],
+		0x2E80..=0x2FDF|0x3000..=0x303F|0x3400..=0x4DBF|0x4E00..=0x9FFF|0xF900..=0xFAFF|0xFF00..=0xFF65|0xFFE0..=0xFFEF|0x20000..=0x3FFFF=>&["ja","zh","ko"],
+		0x200D|0xFE0F|0x2600..=0x27BF|0x1F000..=0x1FAFF=>&["emoji"],
+		_=>&[],
+	}
+}
+#[test]
+fn font_fallback_chain(){
+	let mut config=crate::test_config();
+	config.font_fallbacks=Some([("ja".to_owned(),vec!["IPAexGothic".to_owned()]),("default".to_owned(),vec!["Aileron".to_owned()])].into_iter().collect());
+	let mut db=fontdb::Database::new();
+	db.load_font_source(fontdb::Source::Binary(Arc::new(include_bytes!("../asset/font/Aileron-Light.otf"))));
+	let fonts=Fonts::new(db,&config);
+	assert_eq!(fonts.default_family(),"Aileron");
+	let chain=|c|fonts.fallback_families(c).map(|s|s.as_str()).collect::<Vec<_>>();
+	assert_eq!(chain('あ'),["IPAexGothic","Aileron"]);
+	assert_eq!(chain('漢')[..2],["IPAexGothic","Noto Sans CJK SC"]);
+	assert_eq!(chain('😀')[0],"Noto Color Emoji");
+	assert_eq!(chain('a'),["Aileron"]);
+	let id=fonts.db.faces().next().unwrap().id;
+	assert!(fonts.has_char(&fonts.db,id,'a'));
+	assert!(!fonts.has_char(&fonts.db,id,'あ'));
+	assert_eq!(fonts.coverage.lock().unwrap().len(),1);
+	//読み込まれている日本語の代替フォントを優先する
+	config.font_fallbacks=Some([("ja".to_owned(),vec!["IPAexGothic".to_owned(),"Aileron".to_owned()])].into_iter().collect());
+	let mut db=fontdb::Database::new();
+	db.load_font_source(fontdb::Source::Binary(Arc::new(include_bytes!("../asset/font/Aileron-Light.otf"))));
+	assert_eq!(Fonts::new(db,&config).default_family(),"Aileron");
+	config.font_fallbacks=None;
+	assert_eq!(Fonts::new(fontdb::Database::new(),&config).default_family(),"Aileron");
+}
@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+
+//ホスト名のパターン
+//"example.com"はexample.comとそのサブドメイン、"*"を含む場合はワイルドカードとして扱う
+struct HostMatcher{
+	domains:HashSet<String>,
+	wildcards:Vec<String>,
+}
+impl HostMatcher{
+	fn new(patterns:&[String])->Result<Self,String>{
+		let mut domains=HashSet::new();
+		let mut wildcards=vec![];
+		for s in patterns{
+			let s=s.trim().trim_end_matches('.');
+			if s.is_empty(){
+				continue;
+			}
+			let pattern=normalize(s).ok_or_else(||format!("invalid host pattern: {}",s))?;
+			if pattern.contains('*'){
+				wildcards.push(pattern);
+			}else{
+				domains.insert(pattern);
+			}
+		}
+		Ok(Self{
+			domains,
+			wildcards,
+		})
+	}
+	fn matches(&self,host:&str)->bool{
+		let mut suffix=host;
+		loop{
+			if self.domains.contains(suffix){
+				return true;
+			}
+			match suffix.find('.'){
+				Some(idx)=>suffix=&suffix[idx+1..],
+				None=>break,
+			}
+		}
+		self.wildcards.iter().any(|pattern|wildcard_match(pattern.as_bytes(),host.as_bytes()))
+	}
+}
+pub(crate) struct HostPolicy{
+	blocked_hosts:HostMatcher,
+	allowed_hosts:Option<HostMatcher>,
+	allowed_ports:Option<Vec<u16>>,
+}
+impl HostPolicy{
+	pub fn new(blocked_hosts:Option<&Vec<String>>,allowed_hosts:Option<&Vec<String>>,allowed_ports:Option<&Vec<u16>>)->Result<Self,String>{
+		Ok(Self{
+			blocked_hosts:HostMatcher::new(blocked_hosts.map(|v|v.as_slice()).unwrap_or_default())?,
+			allowed_hosts:allowed_hosts.map(|v|HostMatcher::new(v)).transpose()?,
+			allowed_ports:allowed_ports.cloned(),
+		})
+	}
+	pub fn check(&self,u:&reqwest::Url)->Result<(),String>{
+		match u.scheme().to_lowercase().as_str(){
+			"http"|"https"=>{},
+			scheme=>return Err(format!("scheme: {}",scheme))
+		}
+		let host=u.host_str().ok_or_else(||"no host".to_owned())?;
+		//Urlのホスト名はpunycodeかつ小文字に正規化されている
+		let host=host.trim_end_matches('.');
+		if let Some(allowed_ports)=&self.allowed_ports{
+			let port=u.port_or_known_default().unwrap_or(0);
+			if !allowed_ports.contains(&port){
+				return Err(format!("Blocked port: {}",port));
+			}
+		}
+		if self.blocked_hosts.matches(host){
+			return Err(format!("Blocked host: {}",host));
+		}
+		if let Some(allowed_hosts)=&self.allowed_hosts{
+			if !allowed_hosts.matches(host){
+				return Err(format!("Not allowed host: {}",host));
+			}
+		}
+		Ok(())
+	}
+}
+fn normalize(pattern:&str)->Option<String>{
+	let mut labels=vec![];
+	for label in pattern.split('.'){
+		if label.contains('*'){
+			labels.push(label.to_lowercase());
+		}else{
+			labels.push(idna::domain_to_ascii(label).ok()?);
+		}
+	}
+	Some(labels.join("."))
+}
+fn wildcard_match(pattern:&[u8],host:&[u8])->bool{
+	match pattern.split_first(){
+		None=>host.is_empty(),
+		Some((b'*',rest))=>(0..=host.len()).any(|i|wildcard_match(rest,&host[i..])),
+		Some((c,rest))=>host.first()==Some(c)&&wildcard_match(rest,&host[1..]),
+	}
+}
+#[test]
+fn host_policy_patterns(){
+	let blocked=vec!["Evil.Example".to_owned(),"*.cdn.example".to_owned(),"img*.test".to_owned(),"例え.jp.".to_owned()];
+	let policy=HostPolicy::new(Some(&blocked),None,None).unwrap();
+	let check=|url:&str|policy.check(&url.parse().unwrap());
+	assert!(check("https://evil.example/").is_err());
+	assert!(check("https://cdn.evil.example./a.png").is_err());
+	assert!(check("https://a.cdn.example/").is_err());
+	assert!(check("https://cdn.example/").is_ok());
+	assert!(check("https://img01.test/").is_err());
+	assert!(check("https://www.例え.jp/").is_err());
+	assert!(check("https://xn--r8jz45g.jp/").is_err());
+	assert!(check("https://notevil.example/").is_ok());
+	assert!(check("ftp://example.com/").is_err());
+}
+#[test]
+fn host_policy_allowlist(){
+	let allowed=vec!["media.example".to_owned()];
+	let ports=vec![443];
+	let policy=HostPolicy::new(None,Some(&allowed),Some(&ports)).unwrap();
+	let check=|url:&str|policy.check(&url.parse().unwrap());
+	assert!(check("https://media.example/a.png").is_ok());
+	assert!(check("https://s3.media.example/a.png").is_ok());
+	assert!(check("https://other.example/a.png").is_err());
+	assert!(check("http://media.example/a.png").is_err());
+	assert!(check("https://media.example:8443/a.png").is_err());
+}
@@ -2,26 +2,70 @@
 use axum::response::IntoResponse;
 use image::{AnimationDecoder, DynamicImage, GenericImage, GenericImageView};
 
-use crate::RequestContext;
+use crate::{avif::AvifOptions, decode_limit::DecodeLimits, negotiate::{anim_format, OutputFormat}, RequestContext};
 
 impl RequestContext{
 	pub(crate) fn image_size_hint(&self)->(u32,u32){
 		if self.parms.badge.is_some(){
 			return (96,96);
 		}
-		if self.parms.r#static.is_some(){
-			return (498,422);
-		}
-		if self.parms.emoji.is_some(){
-			return (u32::MAX,128);
-		}
-		if self.parms.preview.is_some(){
-			return (200,200);
-		}
-		if self.parms.avatar.is_some(){
-			return (u32::MAX,320);
+		let (width,height)=if self.parms.r#static.is_some(){
+			(498,422)
+		}else if self.parms.emoji.is_some(){
+			(u32::MAX,128)
+		}else if self.parms.preview.is_some(){
+			(200,200)
+		}else if self.parms.avatar.is_some(){
+			(u32::MAX,320)
+		}else{
+			return (self.config.max_pixels,self.config.max_pixels);
+		};
+		//高解像度の画面向けに大きく描画する
+		let scale=|v:u32|if v==u32::MAX{v}else{(v as f32*self.dpr).round() as u32};
+		(scale(width),scale(height))
+	}
+	//モード別の設定に使う名前、image_size_hintと同じ優先順
+	pub(crate) fn mode_name(&self)->Option<&'static str>{
+		let p=&self.parms;
+		if p.badge.is_some(){
+			Some("badge")
+		}else if p.r#static.is_some(){
+			Some("static")
+		}else if p.emoji.is_some(){
+			Some("emoji")
+		}else if p.preview.is_some(){
+			Some("preview")
+		}else if p.avatar.is_some(){
+			Some("avatar")
+		}else{
+			None
 		}
-		(self.config.max_pixels,self.config.max_pixels)
+	}
+	//変換モードが指定されている場合は出力サイズに合わせて拡大する
+	pub(crate) fn has_size_mode(&self)->bool{
+		let p=&self.parms;
+		p.badge.is_some()||p.r#static.is_some()||p.emoji.is_some()||p.preview.is_some()||p.avatar.is_some()
+	}
+	//デコード後の画像に必要なメモリ量の見積もり
+	pub(crate) fn decoded_size_hint(&self)->u64{
+		let limits=DecodeLimits::new(&self.config);
+		let size=match &self.codec{
+			Ok(codec)=>image::ImageReader::with_format(std::io::Cursor::new(&self.src_bytes),*codec).into_dimensions().ok(),
+			Err(_)=>match self.headers.get("Content-Type").map(|s|s.as_bytes()){
+				Some(b"image/jxl")=>jxl_size(&self.src_bytes),
+				Some(b"image/jxr")=>jxr_size(&self.src_bytes).ok().map(|(width,height)|(width.max(0) as u32,height.max(0) as u32)),
+				_=>crate::decode_limit::probe_jp2(&self.src_bytes),
+			},
+		};
+		//サイズが読めない形式は上限で見積もる
+		let pixels=size.map(|(width,height)|width as u64*height as u64).unwrap_or(u64::MAX).min(limits.max_pixels());
+		let frames=match self.codec{
+			//libwebpは全フレームを同時に展開する
+			Ok(image::ImageFormat::WebP)=>limits.anim_frames_hint(pixels,crate::decode_limit::webp_frames(&self.src_bytes)),
+			_=>1,
+		};
+		//RGBAの展開後の画像とリサイズ後の画像
+		(pixels*4*frames).saturating_add(pixels*4)
 	}
 	pub(crate) fn resize(&self,img:DynamicImage)->Option<DynamicImage>{
 		let (width,height)=self.image_size_hint();
@@ -63,12 +107,19 @@ impl RequestContext{
 		if self.parms.badge.is_some(){
 			return self.encode_single();
 		}
+		let limits=DecodeLimits::new(&self.config);
 		let codec=match &self.codec{
 			Ok(codec) => codec,
 			Err(e) => {
 				match self.headers.get("Content-Type").map(|s|std::str::from_utf8(s.as_bytes())){
 					Some(Ok("image/jxl"))=>{
 						let decoder = jxl_oxide::integration::JxlDecoder::new(std::io::Cursor::new(&self.src_bytes));
+						if let Ok(decoder)=&decoder{
+							let (width,height)=image::ImageDecoder::dimensions(decoder);
+							if let Err(e)=limits.check(width,height){
+								return self.decode_limit_error(e);
+							}
+						}
 						let img=decoder.map(|decoder|DynamicImage::from_decoder(decoder)).unwrap_or_else(|e|Err(e));
 						let img=match img{
 							Ok(img) => img,
@@ -80,6 +131,11 @@ impl RequestContext{
 						return self.response_img(img);
 					}
 					Some(Ok("image/jp2"))=>{
+						//サイズが読めないものはデコードしない
+						let size=crate::decode_limit::probe_jp2(&self.src_bytes).ok_or_else(||"DecodeLimit unknown size".to_owned());
+						if let Err(e)=size.and_then(|(width,height)|limits.check(width,height)){
+							return self.decode_limit_error(e);
+						}
 						let img=jpeg2k::Image::from_bytes(&self.src_bytes).map(|img|DynamicImage::try_from(&img));
 						let img=img.map(|r|r.map_err(|e|e.to_string())).map_err(|e|e.to_string()).unwrap_or_else(|e|Err(e));
 						let img=match img{
@@ -106,6 +162,11 @@ impl RequestContext{
 							let img=jpegxr_img(width as u32,height as u32,stride,buffer,info.format());
 							Ok(img.ok_or_else(||format!("color_format={:?}&bgr={}&channels={}&format={:?}",info.color_format(),info.bgr(),info.channels(),info.format())))
 						}
+						//サイズが読めないものはデコードしない
+						let size=jxr_size(&self.src_bytes).ok().filter(|(width,height)|*width>0&&*height>0).ok_or_else(||"DecodeLimit unknown size".to_owned());
+						if let Err(e)=size.and_then(|(width,height)|limits.check(width as u32,height as u32)){
+							return self.decode_limit_error(e);
+						}
 						match decode_jxr(&self.src_bytes){
 							Ok(Ok(img))=>{
 								return self.response_img(img);
@@ -129,10 +190,14 @@ impl RequestContext{
 		};
 		match codec{
 			image::ImageFormat::Png => {
-				let a=match image::codecs::png::PngDecoder::new(std::io::Cursor::new(&self.src_bytes)){
+				let a=match image::codecs::png::PngDecoder::with_limits(std::io::Cursor::new(&self.src_bytes),limits.image_limits()){
 					Ok(a)=>a,
 					Err(_)=>return self.encode_single()
 				};
+				let (width,height)=image::ImageDecoder::dimensions(&a);
+				if let Err(e)=limits.check(width,height){
+					return self.decode_limit_error(e);
+				}
 				if !a.is_apng().unwrap(){
 					return self.encode_single();
 				}
@@ -146,7 +211,11 @@ impl RequestContext{
 			},
 			image::ImageFormat::Gif => {
 				match image::codecs::gif::GifDecoder::new(std::io::Cursor::new(&self.src_bytes)){
-					Ok(a)=>{
+					Ok(mut a)=>{
+						let (width,height)=image::ImageDecoder::dimensions(&a);
+						if let Err(e)=limits.check(width,height).and_then(|_|image::ImageDecoder::set_limits(&mut a,limits.image_limits()).map_err(crate::decode_limit::error_code)){
+							return self.decode_limit_error(e);
+						}
 						let loop_count=0;//TODO 現在ループ回数を取得するAPIが無いため無限ループ
 						self.encode_anim(a.into_frames(),loop_count)
 					},
@@ -159,6 +228,12 @@ impl RequestContext{
 					Err(_)=>return self.encode_single()
 				};
 				if a.has_animation(){
+					//libwebpは全フレームを一度に展開するので事前に総画素数を確認する
+					let (width,height)=image::ImageDecoder::dimensions(&a);
+					let frames=crate::decode_limit::webp_frames(&self.src_bytes);
+					if let Err(e)=limits.check(width,height).and_then(|_|limits.check_anim(width as u64*height as u64*frames)){
+						return self.decode_limit_error(e);
+					}
 					let decoder=webp::AnimDecoder::new(&self.src_bytes);
 					if let Ok(mut dec)=decoder.decode(){
 						let mut offset=0;
@@ -202,10 +277,14 @@ impl RequestContext{
 	}
 	fn encode_anim(&self,frames:image::Frames,loop_count:u32)->axum::response::Response{
 		let conf=webp::WebPConfig::new().unwrap();
+		//WebPを優先しないクライアントにはGIFで返す
+		let is_gif=self.parms.badge.is_none()&&anim_format(&self.output_formats)==OutputFormat::Legacy;
 		let mut size:Option<(u32, u32)>=None;
 		let mut encoder=None;
 		let mut available_frames=0;
 		let mut err=None;
+		let limits=DecodeLimits::new(&self.config);
+		let mut anim_pixels=0u64;
 		{
 			let mut timestamp=0;
 			const FRAMES_LIMIT:u32=1000;
@@ -218,7 +297,14 @@ impl RequestContext{
 					return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response();
 				}
 				if let Ok(frame)=frame{
-					timestamp+=std::time::Duration::from(frame.delay()).as_millis() as i32;
+					anim_pixels+=frame.buffer().width() as u64*frame.buffer().height() as u64;
+					if let Err(e)=limits.check_anim(anim_pixels){
+						let mut headers=self.headers.clone();
+						headers.append("X-Proxy-Error",e.parse().unwrap());
+						return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response();
+					}
+					let delay=frame.delay();
+					timestamp+=std::time::Duration::from(delay).as_millis() as i32;
 					let img=image::DynamicImage::ImageRgba8(frame.into_buffer());
 					let img=match self.resize(img){
 						Some(img)=>img,
@@ -232,20 +318,25 @@ impl RequestContext{
 						}
 					}else{
 						size=Some((img.width(),img.height()));
-						encoder=Some({
+						encoder=Some(if is_gif{
+							AnimEncoder::Gif(vec![])
+						}else{
 							let mut encoder=webp::AnimEncoder::new(img.width(),img.height(),&conf);
 							encoder.set_loop_count(loop_count.try_into().unwrap_or_default());
-							encoder
+							AnimEncoder::WebP(encoder)
 						});
 					}
-					let aframe=image_to_frame(&img,timestamp);
-					if let Ok(aframe)=aframe{
-						let res=encoder.as_mut().unwrap().add_frame(aframe);
-						if let Err(e)=res{
-							err=Some(e);
-						}else{
-							available_frames+=1;
-						}
+					let res=match encoder.as_mut().unwrap(){
+						AnimEncoder::WebP(encoder)=>image_to_frame(&img,timestamp).ok().map(|aframe|encoder.add_frame(aframe).map_err(|e|format!("{:?}",e))),
+						AnimEncoder::Gif(frames)=>{
+							frames.push(image::Frame::from_parts(img.into_rgba8(),0,0,delay));
+							Some(Ok(()))
+						},
+					};
+					match res{
+						Some(Ok(_))=>available_frames+=1,
+						Some(Err(e))=>err=Some(e),
+						None=>{},
 					}
 				}else{
 					break;
@@ -261,24 +352,37 @@ impl RequestContext{
 			headers.append("X-Proxy-Error","NoAvailableFrames".parse().unwrap());
 			return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response();
 		};
-		let buf=encoder.unwrap().encode();
+		let (buf,mime,ext)=match encoder.unwrap(){
+			AnimEncoder::WebP(encoder)=>(encoder.encode().to_vec(),"image/webp",".webp"),
+			AnimEncoder::Gif(frames)=>{
+				let mut buf=vec![];
+				if let Err(e)=encode_gif(&mut buf,frames,loop_count){
+					headers.append("X-Proxy-Error",format!("EncodeError_{:?}",e).parse().unwrap());
+					return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response();
+				}
+				(buf,"image/gif",".gif")
+			},
+		};
 		headers.remove("Content-Type");
-		headers.append("Content-Type","image/webp".parse().unwrap());
+		headers.append("Content-Type",mime.parse().unwrap());
+		if self.vary_accept{
+			headers.append("Vary","Accept".parse().unwrap());
+		}
 		headers.remove("Cache-Control");
 		if let Some(e)=err{
-			if let Ok(value)=format!("{:?}",e).parse(){
+			if let Ok(value)=e.parse(){
 				headers.append("X-Proxy-Error",value);
 			}
 		}else{
 			headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
 		}
-		Self::disposition_ext(&mut headers,".webp");
-		(axum::http::StatusCode::OK,headers,buf.to_vec()).into_response()
+		Self::disposition_ext(&mut headers,ext);
+		(axum::http::StatusCode::OK,headers,buf).into_response()
 	}
 	fn encode_single(&mut self)->axum::response::Response{
 		let img=match &self.codec{
-			Ok(codec)=>image::load_from_memory_with_format(&self.src_bytes,*codec).map_err(|e|format!("{:?}",e)),
-			Err(Some(e))=>Err(format!("{:?}",e)),
+			Ok(codec)=>DecodeLimits::new(&self.config).load_from_memory(&self.src_bytes,*codec),
+			Err(Some(e))=>Err(format!("DecodeError_{:?}",e)),
 			_=>{
 				self.headers.append("X-Proxy-Error","Unknown Format".parse().unwrap());
 				return (axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response();
@@ -287,13 +391,18 @@ impl RequestContext{
 		let img=match img{
 			Ok(img)=>img,
 			Err(e)=>{
-				self.headers.append("X-Proxy-Error",format!("DecodeError_{}",e).parse().unwrap());
+				self.headers.append("X-Proxy-Error",e.parse().unwrap());
 				return (axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response();
 			}
 		};
 		self.response_img(img)
 	}
+	fn decode_limit_error(&mut self,e:String)->axum::response::Response{
+		self.headers.append("X-Proxy-Error",e.parse().unwrap());
+		(axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response()
+	}
 	pub(crate) fn response_img(&mut self,img:DynamicImage)->axum::response::Response{
+		let src_size=img.dimensions();
 		let img=match self.codec{
 			Ok(image::ImageFormat::Jpeg)|Ok(image::ImageFormat::Tiff)=>{
 				self.exif_rotate(img)
@@ -304,77 +413,107 @@ impl RequestContext{
 			Some(img)=>img,
 			None=>return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
 		};
-		let mut buf=vec![];
 		self.headers.remove("Content-Type");
-		let format=if self.parms.badge.is_some(){
-			self.headers.append("Content-Type","image/png".parse().unwrap());
-			Self::disposition_ext(&mut self.headers,".png");
-			image::ImageFormat::Png
+		let output=if self.parms.badge.is_some(){
+			None
 		}else{
-			if self.is_accept_avif{
-				self.headers.append("Content-Type","image/avif".parse().unwrap());
-				Self::disposition_ext(&mut self.headers,".avif");
-				image::ImageFormat::Avif
-			}else{
+			Some(self.output_formats.first().copied().unwrap_or(OutputFormat::Legacy))
+		};
+		let mut buf=vec![];
+		let (mime,ext,res)=match output{
+			None=>("image/png",".png",img.write_to(&mut std::io::Cursor::new(&mut buf),image::ImageFormat::Png).map_err(|e|format!("{:?}",e))),
+			Some(OutputFormat::Avif)=>{
+				let options=AvifOptions::for_mode(self.config.avif_options.as_ref(),self.config.avif_mode_options.as_ref(),self.mode_name());
+				("image/avif",".avif",options.encode(&img).map(|avif|buf=avif))
+			},
+			Some(OutputFormat::Jxl)=>{
+				let speed=self.config.jxl_speed.unwrap_or(4);
+				//縮小も回転もしていないJPEGは画素を経由せずに可逆に変換する
+				let is_original_jpeg=matches!(self.codec,Ok(image::ImageFormat::Jpeg))&&img.dimensions()==src_size&&matches!(self.exif_orientation(),0|1);
+				let recompressed=if is_original_jpeg{
+					crate::jxl::recompress_jpeg(&self.src_bytes,speed).map_err(|e|println!("jxl recompress {}",e)).ok()
+				}else{
+					None
+				};
+				let res=match recompressed{
+					Some(jxl)=>{
+						buf=jxl;
+						Ok(())
+					},
+					None=>crate::jxl::encode(&img,self.config.jxl_quality.unwrap_or(80f32),speed).map(|jxl|buf=jxl),
+				};
+				("image/jxl",".jxl",res)
+			},
+			Some(OutputFormat::Webp)=>{
 				let width=img.width();
 				let height=img.height();
 				let rgba=img.into_rgba8();
 				let encoer=webp::Encoder::from_rgba(rgba.as_raw(),width,height);
 				let mut config=webp::WebPConfig::new().unwrap();
 				config.quality=self.config.webp_quality;
-				return match encoer.encode_advanced(&config){
-					Ok(mem) => {
-						buf.extend_from_slice(&mem);
-						self.headers.append("Content-Type","image/webp".parse().unwrap());
-						self.headers.remove("Cache-Control");
-						self.headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
-						Self::disposition_ext(&mut self.headers,".webp");
-						(axum::http::StatusCode::OK,self.headers.clone(),buf).into_response()
-					},
-					Err(e) => {
-						self.headers.append("X-Proxy-Error",format!("EncodeError_{:?}",e).parse().unwrap());
-						(axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response()
-					},
-				};
-			}
+				("image/webp",".webp",encoer.encode_advanced(&config).map(|mem|buf.extend_from_slice(&mem)).map_err(|e|format!("{:?}",e)))
+			},
+			//透過がある場合はPNG、無い場合はJPEG
+			Some(OutputFormat::Legacy) if has_transparency(&img)=>("image/png",".png",img.write_to(&mut std::io::Cursor::new(&mut buf),image::ImageFormat::Png).map_err(|e|format!("{:?}",e))),
+			Some(OutputFormat::Legacy)=>{
+				let encoder=image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf,self.config.jpeg_quality.unwrap_or(85));
+				("image/jpeg",".jpg",img.into_rgb8().write_with_encoder(encoder).map_err(|e|format!("{:?}",e)))
+			},
 		};
-		match img.write_to(&mut std::io::Cursor::new(&mut buf),format){
+		match res{
 			Ok(_)=>{
+				self.headers.append("Content-Type",mime.parse().unwrap());
+				if self.vary_accept{
+					self.headers.append("Vary","Accept".parse().unwrap());
+				}
 				self.headers.remove("Cache-Control");
 				self.headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
+				Self::disposition_ext(&mut self.headers,ext);
 				(axum::http::StatusCode::OK,self.headers.clone(),buf).into_response()
 			},
 			Err(e)=>{
-				self.headers.append("X-Proxy-Error",format!("EncodeError_{:?}",e).parse().unwrap());
+				self.headers.append("X-Proxy-Error",format!("EncodeError_{}",e).parse().unwrap());
 				(axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response()
 			}
 		}
 	}
 	pub fn exif_rotate(&self,img:DynamicImage) -> DynamicImage{
+		match self.exif_orientation(){
+			2=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&img)),
+			3=>DynamicImage::ImageRgba8(image::imageops::rotate180(&img)),
+			4=>DynamicImage::ImageRgba8(image::imageops::flip_vertical(&img)),
+			5=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&image::imageops::rotate90(&img))),
+			6=>DynamicImage::ImageRgba8(image::imageops::rotate90(&img)),
+			7=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&image::imageops::rotate270(&img))),
+			8=>DynamicImage::ImageRgba8(image::imageops::rotate270(&img)),
+			_=>img,
+		}
+	}
+	pub fn exif_orientation(&self)->i64{
 		let exifreader = rexif::parse_buffer_quiet(&self.src_bytes);
 		if let Ok(exif)=exifreader.0{
 			for e in exif.entries{
 				match e.tag{
 					rexif::ExifTag::Orientation=>{
-						return match e.value.to_i64(0).unwrap_or(0){
-							2=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&img)),
-							3=>DynamicImage::ImageRgba8(image::imageops::rotate180(&img)),
-							4=>DynamicImage::ImageRgba8(image::imageops::flip_vertical(&img)),
-							5=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&image::imageops::rotate90(&img))),
-							6=>DynamicImage::ImageRgba8(image::imageops::rotate90(&img)),
-							7=>DynamicImage::ImageRgba8(image::imageops::flip_horizontal(&image::imageops::rotate270(&img))),
-							8=>DynamicImage::ImageRgba8(image::imageops::rotate270(&img)),
-							_=>img,
-						};
+						return e.value.to_i64(0).unwrap_or(0);
 					},
 					_=>{}
 				}
 			}
 		}
-		img
+		0
 	}
 }
 
+//ヘッダのみ読んで画像サイズを得る
+fn jxl_size(src_bytes:&[u8])->Option<(u32,u32)>{
+	let decoder=jxl_oxide::integration::JxlDecoder::new(std::io::Cursor::new(src_bytes)).ok()?;
+	Some(image::ImageDecoder::dimensions(&decoder))
+}
+fn jxr_size(src_bytes:&[u8])->Result<(i32,i32), jpegxr::JXRError>{
+	let mut decoder=jpegxr::ImageDecode::with_reader(std::io::Cursor::new(src_bytes))?;
+	decoder.get_size()
+}
 fn jpegxr_img(width:u32,height:u32,stride:usize,buffer:Vec<u8>,info:jpegxr::PixelFormat)->Option<DynamicImage>{
 	match info{
 		jpegxr::PixelFormat::PixelFormat8bppGray => {
@@ -436,6 +575,22 @@ fn jpegxr_img(width:u32,height:u32,stride:usize,buffer:Vec<u8>,info:jpegxr::Pixe
 	}
 }
 
+enum AnimEncoder<'a>{
+	WebP(webp::AnimEncoder<'a>),
+	//GIFはリサイズ後のフレームをまとめてエンコードする
+	Gif(Vec<image::Frame>),
+}
+fn encode_gif(buf:&mut Vec<u8>,frames:Vec<image::Frame>,loop_count:u32)->image::ImageResult<()>{
+	let mut encoder=image::codecs::gif::GifEncoder::new_with_speed(buf,10);
+	encoder.set_repeat(match loop_count{
+		0=>image::codecs::gif::Repeat::Infinite,
+		n=>image::codecs::gif::Repeat::Finite(n.try_into().unwrap_or(u16::MAX)),
+	})?;
+	encoder.encode_frames(frames)
+}
+fn has_transparency(img:&DynamicImage)->bool{
+	img.color().has_alpha()&&img.to_rgba8().pixels().any(|p|p[3]<u8::MAX)
+}
 pub fn image_to_frame(image: &DynamicImage, timestamp: i32) -> Result<webp::AnimFrame, &'static str> {
 	match image {
 		DynamicImage::ImageLuma8(_) => Err("Unimplemented"),
@@ -0,0 +1,193 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+
+//allowed_networksに含まれない限り拒否するIPv4範囲
+//IANA IPv4 Special-Purpose Address Registryのうちグローバルに到達可能でないもの
+//理由の表示に使うため範囲が狭いものを先に書く
+const IPV4_DEFAULT_DENY:[(&str,&str);16]=[
+	("0.0.0.0/8","this network"),
+	("10.0.0.0/8","private-use"),
+	("100.64.0.0/10","shared address space"),
+	("127.0.0.0/8","loopback"),
+	("169.254.0.0/16","link-local"),
+	("172.16.0.0/12","private-use"),
+	("192.0.0.0/24","IETF protocol assignments"),
+	("192.0.2.0/24","documentation"),
+	("192.88.99.0/24","6to4 relay anycast"),
+	("192.168.0.0/16","private-use"),
+	("198.18.0.0/15","benchmarking"),
+	("198.51.100.0/24","documentation"),
+	("203.0.113.0/24","documentation"),
+	("224.0.0.0/4","multicast"),
+	("255.255.255.255/32","limited broadcast"),
+	("240.0.0.0/4","reserved"),
+];
+//allowed_networksに含まれない限り拒否するIPv6範囲
+//IPv4射影アドレス(::ffff:0:0/96)はIPv4のルールで再検査する
+const IPV6_DEFAULT_DENY:[(&str,&str);12]=[
+	("::/128","unspecified"),
+	("::1/128","loopback"),
+	("::/96","IPv4-compatible"),
+	("64:ff9b::/96","NAT64"),
+	("64:ff9b:1::/48","local-use NAT64"),
+	("100::/64","discard-only"),
+	("2001:db8::/32","documentation"),
+	("3fff::/20","documentation"),
+	("fc00::/7","unique local"),
+	("fe80::/10","link-local"),
+	("fec0::/10","site-local"),
+	("ff00::/8","multicast"),
+];
+pub(crate) struct IpPolicy{
+	allow_v4:IpRange<Ipv4Net>,
+	allow_v6:IpRange<Ipv6Net>,
+	block_v4:IpRange<Ipv4Net>,
+	block_v6:IpRange<Ipv6Net>,
+	deny_v4:Vec<(Ipv4Net,&'static str)>,
+	deny_v6:Vec<(Ipv6Net,&'static str)>,
+}
+impl IpPolicy{
+	pub fn new(allowed_networks:Option<&Vec<String>>,blocked_networks:Option<&Vec<String>>)->Result<Self,String>{
+		let mut policy=Self{
+			allow_v4:IpRange::new(),
+			allow_v6:IpRange::new(),
+			block_v4:IpRange::new(),
+			block_v6:IpRange::new(),
+			deny_v4:IPV4_DEFAULT_DENY.iter().map(|(net,reason)|(net.parse().unwrap(),*reason)).collect(),
+			deny_v6:IPV6_DEFAULT_DENY.iter().map(|(net,reason)|(net.parse().unwrap(),*reason)).collect(),
+		};
+		if let Some(networks)=allowed_networks{
+			parse_networks(networks,&mut policy.allow_v4,&mut policy.allow_v6)?;
+		}
+		if let Some(networks)=blocked_networks{
+			parse_networks(networks,&mut policy.block_v4,&mut policy.block_v6)?;
+		}
+		Ok(policy)
+	}
+	pub fn check(&self,ip:IpAddr)->Result<(),String>{
+		match ip{
+			IpAddr::V4(v4)=>self.check_v4(v4),
+			IpAddr::V6(v6)=>self.check_v6(v6),
+		}
+	}
+	fn check_v4(&self,ip:Ipv4Addr)->Result<(),String>{
+		if let Some(net)=self.block_v4.supernet(&ip){
+			return Err(format!("Blocked address: {} blocked_networks {}",ip,net));
+		}
+		if self.allow_v4.contains(&ip){
+			return Ok(());
+		}
+		if let Some((net,reason))=self.deny_v4.iter().find(|(net,_)|net.contains(&ip)){
+			return Err(format!("Blocked address: {} {} {}",ip,reason,net));
+		}
+		Ok(())
+	}
+	fn check_v6(&self,ip:Ipv6Addr)->Result<(),String>{
+		if let Some(net)=self.block_v6.supernet(&ip){
+			return Err(format!("Blocked address: {} blocked_networks {}",ip,net));
+		}
+		if self.allow_v6.contains(&ip){
+			return Ok(());
+		}
+		if let Some(v4)=ip.to_ipv4_mapped(){
+			return self.check_v4(v4);
+		}
+		for v4 in embedded_v4(ip){
+			self.check_v4(v4).map_err(|e|format!("{} embedded in {}",e,ip))?;
+		}
+		if let Some((net,reason))=self.deny_v6.iter().find(|(net,_)|net.contains(&ip)){
+			return Err(format!("Blocked address: {} {} {}",ip,reason,net));
+		}
+		Ok(())
+	}
+}
+//6to4(2002::/16)とTeredo(2001::/32)に埋め込まれたIPv4アドレス
+fn embedded_v4(ip:Ipv6Addr)->Vec<Ipv4Addr>{
+	let o=ip.octets();
+	match ip.segments(){
+		[0x2002,..]=>vec![Ipv4Addr::new(o[2],o[3],o[4],o[5])],
+		//Teredoはサーバのアドレスと反転したクライアントのアドレスを含む
+		[0x2001,0,..]=>vec![Ipv4Addr::new(o[4],o[5],o[6],o[7]),Ipv4Addr::new(!o[12],!o[13],!o[14],!o[15])],
+		_=>vec![],
+	}
+}
+pub(crate) fn parse_networks(networks:&[String],v4:&mut IpRange<Ipv4Net>,v6:&mut IpRange<Ipv6Net>)->Result<(),String>{
+	for s in networks{
+		let s=s.trim();
+		if s.is_empty(){
+			continue;
+		}
+		//プレフィックス長が無い場合は単一アドレスとして扱う
+		let net=match s.parse::<IpNet>(){
+			Ok(net)=>net,
+			Err(_)=>IpNet::from(s.parse::<IpAddr>().map_err(|_|format!("invalid network: {}",s))?),
+		};
+		match net.trunc(){
+			IpNet::V4(net)=>{
+				v4.add(net);
+			},
+			IpNet::V6(net)=>{
+				v6.add(net);
+			},
+		}
+	}
+	v4.simplify();
+	v6.simplify();
+	Ok(())
+}
+#[test]
+fn ipv6_default_deny(){
+	let policy=IpPolicy::new(None,None).unwrap();
+	for ip in ["::1","::","fd00::1","fe80::1","ff02::1","64:ff9b::a00:1","2001:db8::1"]{
+		assert!(policy.check(ip.parse().unwrap()).is_err(),"{}",ip);
+	}
+	assert!(policy.check("2606:4700::1111".parse().unwrap()).is_ok());
+}
+#[test]
+fn ipv4_mapped_recheck(){
+	let policy=IpPolicy::new(None,None).unwrap();
+	assert!(policy.check("::ffff:10.0.0.1".parse().unwrap()).is_err());
+	assert!(policy.check("::ffff:1.1.1.1".parse().unwrap()).is_ok());
+	let allowed=vec!["10.0.0.0/8".to_owned()];
+	let policy=IpPolicy::new(Some(&allowed),None).unwrap();
+	assert!(policy.check("::ffff:10.0.0.1".parse().unwrap()).is_ok());
+}
+#[test]
+fn ipv4_embedded_recheck(){
+	let policy=IpPolicy::new(None,None).unwrap();
+	//6to4
+	assert!(policy.check("2002:7f00:1::1".parse().unwrap()).is_err());
+	assert!(policy.check("2002:a00:5::1".parse().unwrap()).is_err());
+	assert!(policy.check("2002:101:101::1".parse().unwrap()).is_ok());
+	//Teredo、クライアントは反転して埋め込まれる
+	assert!(policy.check("2001:0:101:101::80ff:fffe".parse().unwrap()).is_err());
+	assert!(policy.check("2001:0:c0a8:1::fefe:fefe".parse().unwrap()).is_err());
+	assert!(policy.check("2001:0:101:101::fefe:fefe".parse().unwrap()).is_ok());
+}
+#[test]
+fn ipv6_networks_config(){
+	let allowed=vec!["fd00:1::/64".to_owned()];
+	let blocked=vec!["2606:4700::/32".to_owned(),"1.1.1.1".to_owned()];
+	let policy=IpPolicy::new(Some(&allowed),Some(&blocked)).unwrap();
+	assert!(policy.check("fd00:1::5".parse().unwrap()).is_ok());
+	assert!(policy.check("fd00:2::5".parse().unwrap()).is_err());
+	assert!(policy.check("2606:4700::1111".parse().unwrap()).is_err());
+	assert!(policy.check("1.1.1.1".parse().unwrap()).is_err());
+	assert!(IpPolicy::new(Some(&vec!["not-a-network".to_owned()]),None).is_err());
+}
+#[test]
+fn ipv4_special_purpose(){
+	let policy=IpPolicy::new(None,None).unwrap();
+	for ip in ["127.0.0.1","169.254.169.254","100.64.0.1","0.0.0.0","198.18.0.1","192.0.0.170","224.0.0.1","255.255.255.255","240.0.0.1"]{
+		assert!(policy.check(ip.parse().unwrap()).is_err(),"{}",ip);
+	}
+	assert!(policy.check("1.1.1.1".parse().unwrap()).is_ok());
+	let e=policy.check("169.254.169.254".parse().unwrap()).unwrap_err();
+	assert!(e.contains("link-local"),"{}",e);
+	let allowed=vec!["127.0.0.1/32".to_owned()];
+	let policy=IpPolicy::new(Some(&allowed),None).unwrap();
+	assert!(policy.check("127.0.0.1".parse().unwrap()).is_ok());
+	assert!(policy.check("127.0.0.2".parse().unwrap()).is_err());
+}
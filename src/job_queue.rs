@@ -0,0 +1,76 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+//画像のデコード/エンコードの同時実行数を制限する
+pub(crate) struct JobQueue{
+	permits:Arc<Semaphore>,
+	max_jobs:usize,
+	max_waiting:usize,
+	waiting:AtomicUsize,
+	wait_timeout:Duration,
+}
+pub(crate) struct JobPermit{
+	_permit:OwnedSemaphorePermit,
+}
+struct WaitingGuard<'a>(&'a AtomicUsize);
+impl Drop for WaitingGuard<'_>{
+	fn drop(&mut self){
+		self.0.fetch_sub(1,Ordering::SeqCst);
+	}
+}
+impl JobQueue{
+	pub fn new(max_jobs:usize,max_waiting:usize,wait_timeout:Duration)->Self{
+		let max_jobs=max_jobs.max(1);
+		Self{
+			permits:Arc::new(Semaphore::new(max_jobs)),
+			max_jobs,
+			max_waiting,
+			waiting:AtomicUsize::new(0),
+			wait_timeout,
+		}
+	}
+	pub fn running(&self)->usize{
+		self.max_jobs-self.permits.available_permits()
+	}
+	pub fn waiting(&self)->usize{
+		self.waiting.load(Ordering::SeqCst)
+	}
+	pub async fn acquire(&self)->Result<JobPermit,String>{
+		if let Ok(permit)=self.permits.clone().try_acquire_owned(){
+			return Ok(JobPermit{_permit:permit});
+		}
+		//待ち行列が上限に達している場合は待たずに拒否する
+		if self.waiting.fetch_add(1,Ordering::SeqCst)>=self.max_waiting{
+			self.waiting.fetch_sub(1,Ordering::SeqCst);
+			return Err("QueueFull".to_owned());
+		}
+		//クライアントが切断してもカウントが戻るようにDropで減らす
+		let _waiting=WaitingGuard(&self.waiting);
+		let res=tokio::time::timeout(self.wait_timeout,self.permits.clone().acquire_owned()).await;
+		match res{
+			Ok(Ok(permit))=>Ok(JobPermit{_permit:permit}),
+			Ok(Err(_))=>Err("QueueClosed".to_owned()),
+			Err(_)=>Err("QueueTimeout".to_owned()),
+		}
+	}
+}
+#[test]
+fn job_queue_admission(){
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let queue=JobQueue::new(1,1,Duration::from_millis(50));
+	rt.block_on(async{
+		let permit=queue.acquire().await.unwrap();
+		assert_eq!(queue.running(),1);
+		let waiter=queue.acquire();
+		futures::pin_mut!(waiter);
+		//1件目は待ち行列に入る
+		assert!(futures::poll!(waiter.as_mut()).is_pending());
+		assert_eq!(queue.waiting(),1);
+		assert_eq!(queue.acquire().await.err().unwrap(),"QueueFull");
+		assert_eq!(waiter.await.err().unwrap(),"QueueTimeout");
+		assert_eq!(queue.waiting(),0);
+		drop(permit);
+		assert!(queue.acquire().await.is_ok());
+	});
+}
@@ -0,0 +1,67 @@
+use image::DynamicImage;
+
+//jpegxl-rsはGPLなのでjxl-encoder featureを有効にした場合のみ使う
+//speedはavifと同じ向きでlibjxlのeffortとは逆になる
+#[cfg(feature="jxl-encoder")]
+fn encoder_speed(speed:u8)->jpegxl_rs::encode::EncoderSpeed{
+	use jpegxl_rs::encode::EncoderSpeed;
+	match speed.clamp(1,10){
+		1=>EncoderSpeed::Glacier,
+		2=>EncoderSpeed::Tortoise,
+		3=>EncoderSpeed::Kitten,
+		4=>EncoderSpeed::Squirrel,
+		5=>EncoderSpeed::Wombat,
+		6=>EncoderSpeed::Hare,
+		7=>EncoderSpeed::Cheetah,
+		8=>EncoderSpeed::Falcon,
+		9=>EncoderSpeed::Thunder,
+		_=>EncoderSpeed::Lightning,
+	}
+}
+//qualityはJPEGと同じ0から100、speedは1(遅い、高圧縮)から10(速い)
+#[cfg(feature="jxl-encoder")]
+pub(crate) fn encode(img:&DynamicImage,quality:f32,speed:u8)->Result<Vec<u8>,String>{
+	let has_alpha=img.color().has_alpha();
+	let (data,channels)=if has_alpha{
+		(img.to_rgba8().into_raw(),4)
+	}else{
+		(img.to_rgb8().into_raw(),3)
+	};
+	let mut encoder=jpegxl_rs::encoder_builder()
+		.has_alpha(has_alpha)
+		.speed(encoder_speed(speed))
+		.jpeg_quality(quality.clamp(0f32,100f32))
+		.build().map_err(|e|format!("{:?}",e))?;
+	let frame=jpegxl_rs::encode::EncoderFrame::new(&data).num_channels(channels);
+	encoder.encode_frame::<u8,u8>(&frame,img.width(),img.height()).map(|r|r.data).map_err(|e|format!("{:?}",e))
+}
+//JPEGを画素に展開せずに可逆に変換する
+#[cfg(feature="jxl-encoder")]
+pub(crate) fn recompress_jpeg(src:&[u8],speed:u8)->Result<Vec<u8>,String>{
+	let mut encoder=jpegxl_rs::encoder_builder()
+		.uses_original_profile(true)
+		.use_container(true)
+		.speed(encoder_speed(speed))
+		.build().map_err(|e|format!("{:?}",e))?;
+	encoder.encode_jpeg(src).map(|r|r.data).map_err(|e|format!("{:?}",e))
+}
+#[cfg(not(feature="jxl-encoder"))]
+pub(crate) fn encode(_img:&DynamicImage,_quality:f32,_speed:u8)->Result<Vec<u8>,String>{
+	Err("JxlEncoderDisabled".to_owned())
+}
+#[cfg(not(feature="jxl-encoder"))]
+pub(crate) fn recompress_jpeg(_src:&[u8],_speed:u8)->Result<Vec<u8>,String>{
+	Err("JxlEncoderDisabled".to_owned())
+}
+#[cfg(feature="jxl-encoder")]
+#[test]
+fn jxl_encode(){
+	let img=DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(16,16,image::Rgba([255,0,0,128])));
+	let jxl=encode(&img,80f32,4).unwrap();
+	let img=jxl_oxide::JxlImage::builder().read(std::io::Cursor::new(&jxl)).unwrap();
+	assert_eq!((img.width(),img.height()),(16,16));
+	let mut jpeg=vec![];
+	DynamicImage::ImageRgb8(image::RgbImage::from_pixel(16,16,image::Rgb([0,255,0]))).write_to(&mut std::io::Cursor::new(&mut jpeg),image::ImageFormat::Jpeg).unwrap();
+	let jxl=recompress_jpeg(&jpeg,4).unwrap();
+	assert!(jxl_oxide::JxlImage::builder().read(std::io::Cursor::new(&jxl)).is_ok());
+}
@@ -1,5 +1,5 @@
 use core::str;
-use std::{io::Write, net::SocketAddr, pin::Pin, str::FromStr, sync::Arc};
+use std::{io::Write, net::SocketAddr, str::FromStr, sync::Arc};
 
 use axum::{http::HeaderMap, response::IntoResponse, Router};
 use serde::{Deserialize, Serialize};
@@ -7,7 +7,25 @@ use tokio_stream::StreamExt;
 
 mod img;
 mod svg;
+mod svg_sanitize;
+mod fonts;
 mod browsersafe;
+mod ip_policy;
+mod host_policy;
+mod signature;
+mod rate_limit;
+mod resolver;
+mod job_queue;
+mod decode_limit;
+mod memory_budget;
+mod spill;
+mod passthrough;
+mod media_policy;
+mod sniff;
+mod content_encoding;
+mod negotiate;
+mod avif;
+mod jxl;
 mod image_test;
 
 #[derive(Debug,Serialize,Deserialize)]
@@ -23,9 +41,68 @@ pub struct ConfigFile{
 	load_system_fonts:bool,
 	webp_quality:f32,
 	encode_avif:bool,
+	output_formats:Option<Vec<negotiate::OutputFormat>>,
+	jpeg_quality:Option<u8>,
+	avif_options:Option<avif::AvifOptions>,
+	avif_mode_options:Option<std::collections::HashMap<String,avif::AvifOptions>>,
+	encode_jxl:Option<bool>,
+	jxl_quality:Option<f32>,
+	jxl_speed:Option<u8>,
 	allowed_networks:Option<Vec<String>>,
 	blocked_networks:Option<Vec<String>>,
 	blocked_hosts:Option<Vec<String>>,
+	allowed_hosts:Option<Vec<String>>,
+	allowed_ports:Option<Vec<u16>>,
+	max_redirects:Option<usize>,
+	dns_nameservers:Option<Vec<String>>,
+	dns_timeout:Option<u64>,
+	dns_positive_ttl:Option<u64>,
+	dns_negative_ttl:Option<u64>,
+	dns_ip_strategy:Option<DnsIpStrategy>,
+	signing_secret:Option<String>,
+	signing_grace:Option<bool>,
+	client_rate_limit:Option<f64>,
+	client_rate_burst:Option<u32>,
+	host_rate_limit:Option<f64>,
+	host_rate_burst:Option<u32>,
+	trusted_proxies:Option<Vec<String>>,
+	max_image_jobs:Option<usize>,
+	max_image_queue:Option<usize>,
+	image_queue_timeout:Option<u64>,
+	max_decode_width:Option<u32>,
+	max_decode_height:Option<u32>,
+	max_decode_pixels:Option<u64>,
+	max_anim_pixels:Option<u64>,
+	memory_budget:Option<u64>,
+	memory_budget_timeout:Option<u64>,
+	spill_threshold:Option<u64>,
+	spill_dir:Option<String>,
+	sniff_window:Option<usize>,
+	emoji_svg_output:Option<bool>,
+	svg_max_nodes:Option<usize>,
+	svg_max_filter_pixels:Option<u64>,
+	svg_render_timeout:Option<u64>,
+	svg_device_pixel_ratio:Option<f32>,
+	font_dirs:Option<Vec<String>>,
+	font_family:Option<String>,
+	font_fallbacks:Option<std::collections::HashMap<String,Vec<String>>>,
+	passthrough_max_size:Option<u64>,
+	passthrough_idle_timeout:Option<u64>,
+	passthrough_max_duration:Option<u64>,
+	passthrough_rate_limit:Option<u64>,
+	passthrough_allowed_types:Option<Vec<String>>,
+	passthrough_denied_types:Option<Vec<String>>,
+	passthrough_type_max_size:Option<std::collections::HashMap<String,u64>>,
+	passthrough_fallback_type:Option<String>,
+	passthrough_fallback_disposition:Option<String>,
+}
+//テスト用の最小限の設定、各テストで必要な項目を上書きする
+#[cfg(test)]
+pub(crate) fn test_config()->ConfigFile{
+	serde_json::from_str(r#"{
+		"bind_addr":"0.0.0.0:12766","timeout":10000,"user_agent":"","max_size":1000,"filter_type":"Triangle","max_pixels":2048,
+		"append_headers":[],"load_system_fonts":false,"webp_quality":75,"encode_avif":false
+	}"#).unwrap()
 }
 #[derive(Debug, Deserialize)]
 pub struct RequestParams{
@@ -37,6 +114,29 @@ pub struct RequestParams{
 	preview:Option<String>,
 	badge:Option<String>,
 	fallback:Option<String>,
+	expires:Option<u64>,
+	sig:Option<String>,
+}
+impl RequestParams{
+	fn signed_modes(&self)->Vec<&'static str>{
+		let mut modes=vec![];
+		if self.r#static.is_some(){
+			modes.push("static");
+		}
+		if self.emoji.is_some(){
+			modes.push("emoji");
+		}
+		if self.avatar.is_some(){
+			modes.push("avatar");
+		}
+		if self.preview.is_some(){
+			modes.push("preview");
+		}
+		if self.badge.is_some(){
+			modes.push("badge");
+		}
+		modes
+	}
 }
 #[derive(Clone, Copy,Debug,Serialize,Deserialize)]
 enum FilterType{
@@ -46,6 +146,25 @@ enum FilterType{
 	Gaussian,
 	Lanczos3,
 }
+#[derive(Clone, Copy,Debug,Serialize,Deserialize)]
+enum DnsIpStrategy{
+	Ipv4Only,
+	Ipv6Only,
+	Ipv4AndIpv6,
+	Ipv4ThenIpv6,
+	Ipv6ThenIpv4,
+}
+impl From<DnsIpStrategy> for hickory_resolver::config::LookupIpStrategy{
+	fn from(value:DnsIpStrategy) -> Self {
+		match value {
+			DnsIpStrategy::Ipv4Only => hickory_resolver::config::LookupIpStrategy::Ipv4Only,
+			DnsIpStrategy::Ipv6Only => hickory_resolver::config::LookupIpStrategy::Ipv6Only,
+			DnsIpStrategy::Ipv4AndIpv6 => hickory_resolver::config::LookupIpStrategy::Ipv4AndIpv6,
+			DnsIpStrategy::Ipv4ThenIpv6 => hickory_resolver::config::LookupIpStrategy::Ipv4thenIpv6,
+			DnsIpStrategy::Ipv6ThenIpv4 => hickory_resolver::config::LookupIpStrategy::Ipv6thenIpv4,
+		}
+	}
+}
 impl Into<image::imageops::FilterType> for FilterType{
 	fn into(self) -> image::imageops::FilterType {
 		match self {
@@ -119,9 +238,60 @@ fn main() {
 			load_system_fonts:true,
 			webp_quality: 75f32,
 			encode_avif:false,
+			output_formats:None,
+			jpeg_quality:None,
+			avif_options:None,
+			avif_mode_options:None,
+			encode_jxl:None,
+			jxl_quality:None,
+			jxl_speed:None,
 			allowed_networks:None,
 			blocked_networks:None,
 			blocked_hosts:None,
+			allowed_hosts:None,
+			allowed_ports:None,
+			max_redirects:None,
+			dns_nameservers:None,
+			dns_timeout:None,
+			dns_positive_ttl:None,
+			dns_negative_ttl:None,
+			dns_ip_strategy:None,
+			signing_secret:None,
+			signing_grace:None,
+			client_rate_limit:None,
+			client_rate_burst:None,
+			host_rate_limit:None,
+			host_rate_burst:None,
+			trusted_proxies:None,
+			max_image_jobs:None,
+			max_image_queue:None,
+			image_queue_timeout:None,
+			max_decode_width:None,
+			max_decode_height:None,
+			max_decode_pixels:None,
+			max_anim_pixels:None,
+			memory_budget:None,
+			memory_budget_timeout:None,
+			spill_threshold:None,
+			spill_dir:None,
+			sniff_window:None,
+			emoji_svg_output:None,
+			svg_max_nodes:None,
+			svg_max_filter_pixels:None,
+			svg_render_timeout:None,
+			svg_device_pixel_ratio:None,
+			font_dirs:None,
+			font_family:None,
+			font_fallbacks:None,
+			passthrough_max_size:None,
+			passthrough_idle_timeout:None,
+			passthrough_max_duration:None,
+			passthrough_rate_limit:None,
+			passthrough_allowed_types:None,
+			passthrough_denied_types:None,
+			passthrough_type_max_size:None,
+			passthrough_fallback_type:None,
+			passthrough_fallback_disposition:None,
 		};
 		let default_config=serde_json::to_string_pretty(&default_config).unwrap();
 		std::fs::File::create(&config_path).expect("create default config.json").write_all(default_config.as_bytes()).unwrap();
@@ -148,6 +318,22 @@ fn main() {
 		}
 		config.blocked_hosts.replace(blocked_hosts);
 	}
+	if let Ok(hosts)=std::env::var("MEDIA_PROXY_ALLOWED_HOSTS"){
+		let mut allowed_hosts=config.allowed_hosts.take().unwrap_or_default();
+		for host in hosts.split(","){
+			allowed_hosts.push(host.to_owned());
+		}
+		config.allowed_hosts.replace(allowed_hosts);
+	}
+	if let Ok(secret)=std::env::var("MEDIA_PROXY_SIGNING_SECRET"){
+		if !secret.is_empty(){
+			config.signing_secret.replace(secret);
+		}
+	}
+	if config.encode_jxl.unwrap_or(false)&&!negotiate::is_jxl_enabled(&config){
+		println!("encode_jxl requires the jxl-encoder feature");
+	}
+	avif::init_thread_pools(config.avif_options.as_ref(),config.avif_mode_options.as_ref());
 	let dummy_png=Arc::new(include_bytes!("../asset/dummy.png").to_vec());
 	let config=Arc::new(config);
 	let rt=tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
@@ -156,89 +342,71 @@ fn main() {
 		Some(url)=>client.proxy(reqwest::Proxy::http(url).unwrap()),
 		None=>client,
 	};
+	let host_policy=Arc::new(host_policy::HostPolicy::new(config.blocked_hosts.as_ref(),config.allowed_hosts.as_ref(),config.allowed_ports.as_ref()).expect("blocked_hosts/allowed_hosts"));
+	let policy=Arc::new(ip_policy::IpPolicy::new(config.allowed_networks.as_ref(),config.blocked_networks.as_ref()).expect("allowed_networks/blocked_networks"));
+	let lookup=resolver::HickoryLookup::new(
+		config.dns_nameservers.as_ref(),
+		std::time::Duration::from_millis(config.dns_timeout.unwrap_or(3000)),
+		config.dns_positive_ttl.map(std::time::Duration::from_secs),
+		config.dns_negative_ttl.map(std::time::Duration::from_secs),
+		config.dns_ip_strategy.unwrap_or(DnsIpStrategy::Ipv4ThenIpv6).into(),
+	).expect("dns resolver");
+	let resolver=resolver::PinnedResolver::new(policy,Arc::new(lookup),std::time::Duration::from_millis(config.timeout));
+	//プロキシ経由の場合は接続先の名前解決をプロキシが行う
+	let client=if config.proxy.is_none(){
+		client.dns_resolver(Arc::new(resolver.clone()))
+	}else{
+		client
+	};
+	let rate_limit=Arc::new(rate_limit::RateLimit::new(
+		config.client_rate_limit,
+		config.client_rate_burst,
+		config.host_rate_limit,
+		config.host_rate_burst,
+		config.trusted_proxies.as_ref(),
+	).expect("rate limit"));
+	//未設定の場合はCPU数
+	let max_image_jobs=config.max_image_jobs.unwrap_or_else(||std::thread::available_parallelism().map(|n|n.get()).unwrap_or(1));
+	let job_queue=Arc::new(job_queue::JobQueue::new(
+		max_image_jobs,
+		config.max_image_queue.unwrap_or(max_image_jobs*4),
+		std::time::Duration::from_millis(config.image_queue_timeout.unwrap_or(config.timeout)),
+	));
+	let media_policy=Arc::new(media_policy::MediaPolicy::new(&config).expect("passthrough media policy"));
+	let memory_budget=Arc::new(memory_budget::MemoryBudget::new(
+		config.memory_budget.unwrap_or(1024*1024*1024),
+		std::time::Duration::from_millis(config.memory_budget_timeout.unwrap_or(config.timeout)),
+	));
+	let client=client.redirect(resolver.redirect_policy(host_policy.clone(),config.max_redirects.unwrap_or(10)));
 	let client=client.build().unwrap();
-	let mut fontdb=resvg::usvg::fontdb::Database::new();
-	if config.load_system_fonts{
-		fontdb.load_system_fonts();
-	}
-	if std::path::Path::new("asset/font/").exists(){
-		fontdb.load_fonts_dir("asset/font/");
-	}
-	fontdb.load_font_source(resvg::usvg::fontdb::Source::Binary(Arc::new(include_bytes!("../asset/font/Aileron-Light.otf"))));
-	let fontdb=Arc::new(fontdb);
-	let arg_tup=(client,config,dummy_png,fontdb);
+	let fonts=Arc::new(fonts::Fonts::load(&config));
+	let arg_tup=(client,config,dummy_png,fonts,resolver,host_policy,rate_limit,job_queue,memory_budget,media_policy);
 	rt.block_on(async{
 		let http_addr:SocketAddr = arg_tup.1.bind_addr.parse().unwrap();
 		let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
 		let app = Router::new();
 		let arg_tup0=arg_tup.clone();
-		let app=app.route("/",axum::routing::get(move|headers,parms|get_file(None,headers,arg_tup0.clone(),parms)));
-		let app=app.route("/{*path}",axum::routing::get(move|path,headers,parms|get_file(Some(path),headers,arg_tup.clone(),parms)));
+		//ヘルスチェック用、外部への取得は行わない
+		let app=app.route("/healthz",axum::routing::get(||async{"ok"}));
+		let app=app.route("/",axum::routing::get(move|addr,headers,parms|get_file(None,addr,headers,arg_tup0.clone(),parms)));
+		let app=app.route("/{*path}",axum::routing::get(move|path,addr,headers,parms|get_file(Some(path),addr,headers,arg_tup.clone(),parms)));
 		axum::serve(listener,app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown_signal()).await.unwrap();
 	});
 }
-async fn check_url(config:&Arc<ConfigFile>,url:impl AsRef<str>)->Result<(),String>{
+async fn check_url(host_policy:&host_policy::HostPolicy,resolver:&resolver::PinnedResolver,url:impl AsRef<str>)->Result<(),String>{
 	let u=reqwest::Url::from_str(url.as_ref()).map_err(|e|format!("{:?}",e))?;
-	match u.scheme().to_lowercase().as_str(){
-		"http"|"https"=>{},
-		scheme=>return Err(format!("scheme: {}",scheme))
-	}
+	host_policy.check(&u)?;
 	let host=u.host_str().ok_or_else(||"no host".to_owned())?;
-	if let Some(blocked_hosts)=&config.blocked_hosts{
-		if blocked_hosts.contains(&host.to_lowercase()){
-			return Err("Blocked address".to_owned());
-		}
-	}
-	use std::net::{SocketAddr, ToSocketAddrs};
-	use iprange::IpRange;
-	use ipnet::Ipv4Net;
-	let ips=format!("{}:{}",host,u.port_or_known_default().unwrap()).to_socket_addrs().map_err(|e|format!("{:?} {}",e,host))?;
-	let ipv4_private_range: IpRange<Ipv4Net> = ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"]
-		.iter()
-		.map(|s| s.parse().unwrap())
-		.collect();
-	let allow_ips=config.allowed_networks.as_ref().map(|ips|{
-		ips.iter()
-		.map(|s| s.parse().unwrap())
-		.collect::<IpRange<Ipv4Net>>()
-	});
-	let block_ips=config.blocked_networks.as_ref().map(|ips|{
-		ips.iter()
-		.map(|s| s.parse().unwrap())
-		.collect::<IpRange<Ipv4Net>>()
-	});
-	for ip in ips{
-		match ip{
-			SocketAddr::V4(v4) => {
-				if let Some(block_ips)=&block_ips{
-					if block_ips.contains(v4.ip()){
-						return Err("Blocked address".to_owned());
-					}
-				}
-				if ipv4_private_range.contains(v4.ip()){
-					let allow=if let Some(allow_ips)=&allow_ips{
-						allow_ips.contains(v4.ip())
-					}else{
-						false
-					};
-					if !allow{
-						return Err("Blocked address".to_owned());
-					}
-				}
-			},
-			SocketAddr::V6(v6) => {
-				if v6.ip().is_multicast()||v6.ip().is_unicast_link_local(){
-					return Err("Blocked address".to_owned());
-				}
-			},
-		}
-	}
+	resolver.approve(host).await?;
 	Ok(())
 }
+//各リクエストで共有するクライアント、設定、制限等
+type AppState=(reqwest::Client,Arc<ConfigFile>,Arc<Vec<u8>>,Arc<fonts::Fonts>,resolver::PinnedResolver,Arc<host_policy::HostPolicy>,Arc<rate_limit::RateLimit>,Arc<job_queue::JobQueue>,Arc<memory_budget::MemoryBudget>,Arc<media_policy::MediaPolicy>);
 async fn get_file(
 	_path:Option<axum::extract::Path<String>>,
+	axum::extract::ConnectInfo(client_addr):axum::extract::ConnectInfo<SocketAddr>,
 	client_headers:axum::http::HeaderMap,
-	(client,config,dummy_img,fontdb):(reqwest::Client,Arc<ConfigFile>,Arc<Vec<u8>>,Arc<resvg::usvg::fontdb::Database>),
+	(client,config,dummy_img,fonts,resolver,host_policy,rate_limit,job_queue,memory_budget,media_policy):AppState,
 	axum::extract::Query(q):axum::extract::Query<RequestParams>,
 )->Result<(axum::http::StatusCode,HeaderMap,axum::body::Body),axum::response::Response>{
 	println!("{}\t{}\tavatar:{:?}\tpreview:{:?}\tbadge:{:?}\temoji:{:?}\tstatic:{:?}\tfallback:{:?}",
@@ -255,11 +423,42 @@ async fn get_file(
 	if let Ok(url)=q.url.parse(){
 		headers.append("X-Remote-Url",url);
 	}
-	if config.encode_avif{
-		headers.append("Vary","Accept,Range".parse().unwrap());
+	headers.append("X-Content-Type-Options","nosniff".parse().unwrap());
+	headers.append("Vary","Range".parse().unwrap());
+	let output_chain=negotiate::chain(&config);
+	fn rate_limited(mut headers:HeaderMap,retry_after:std::time::Duration,is_fallback:bool,dummy_img:&Arc<Vec<u8>>)->axum::response::Response{
+		headers.append("X-Proxy-Error","RateLimit".parse().unwrap());
+		headers.append("Retry-After",retry_after.as_secs_f64().ceil().max(1f64).to_string().parse().unwrap());
+		if is_fallback{
+			headers.append("Content-Type","image/png".parse().unwrap());
+			return (axum::http::StatusCode::OK,headers,(**dummy_img).clone()).into_response();
+		}
+		(axum::http::StatusCode::TOO_MANY_REQUESTS,headers).into_response()
+	}
+	let client_ip=rate_limit.client_ip(client_addr,&client_headers);
+	if let Err(retry_after)=rate_limit.check_client(client_ip){
+		println!("rate limit {}",client_ip);
+		return Err(rate_limited(headers,retry_after,q.fallback.is_some(),&dummy_img));
+	}
+	if let Some(secret)=&config.signing_secret{
+		let now=chrono::Utc::now().timestamp().max(0) as u64;
+		if let Err(e)=signature::verify(secret.as_bytes(),&q.url,&q.signed_modes(),q.expires,q.sig.as_deref(),now){
+			if config.signing_grace.unwrap_or(false){
+				println!("signature {}\t{}",e,q.url);
+			}else{
+				if let Ok(v)=e.parse(){
+					headers.append("X-Proxy-Error",v);
+				}
+				if q.fallback.is_some(){
+					headers.append("Content-Type","image/png".parse().unwrap());
+					return Err((axum::http::StatusCode::OK,headers,(*dummy_img).clone()).into_response());
+				}
+				return Err((axum::http::StatusCode::FORBIDDEN,headers).into_response())
+			}
+		}
 	}
 	let time=chrono::Utc::now();
-	if let Err(s)=check_url(&config,&q.url).await{
+	if let Err(s)=check_url(&host_policy,&resolver,&q.url).await{
 		if let Ok(v)=s.parse(){
 			headers.append("X-Proxy-Error",v);
 		}
@@ -271,17 +470,41 @@ async fn get_file(
 	};
 
 	println!("check_url {}ms",(chrono::Utc::now()-time).num_milliseconds());
+	if let Ok(u)=reqwest::Url::from_str(&q.url){
+		if let Err(retry_after)=rate_limit.check_host(u.host_str().unwrap_or_default()){
+			println!("rate limit {}",u.host_str().unwrap_or_default());
+			return Err(rate_limited(headers,retry_after,q.fallback.is_some(),&dummy_img));
+		}
+	}
+	//画像として読み込む場合は本文の取得までtimeoutに含める
+	//そのまま転送する場合はpassthrough_*の制限を使う
+	let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(config.timeout);
 	let req=client.get(&q.url);
-	let req=req.timeout(std::time::Duration::from_millis(config.timeout));
 	let req=req.header("User-Agent",config.user_agent.clone());
+	let req=req.header("Accept-Encoding",content_encoding::ACCEPT_ENCODING);
 	let req=if let Some(range)=client_headers.get("Range"){
 		req.header("Range",range.as_bytes())
 	}else{
 		req
 	};
-	let resp=match req.send().await{
-		Ok(resp) => resp,
-		Err(e) => {
+	let resp=match tokio::time::timeout_at(deadline,req.send()).await{
+		Ok(Ok(resp)) => resp,
+		Err(_) => {
+			headers.append("X-Proxy-Error","Timeout".parse().unwrap());
+			if q.fallback.is_some(){
+				headers.append("Content-Type","image/png".parse().unwrap());
+				return Err((axum::http::StatusCode::OK,headers,(*dummy_img).clone()).into_response());
+			}
+			return Err((axum::http::StatusCode::GATEWAY_TIMEOUT,headers).into_response())
+		},
+		Ok(Err(e)) => {
+			if e.is_redirect(){
+				if let Some(source)=std::error::Error::source(&e){
+					if let Ok(v)=format!("Redirect: {}",source).parse(){
+						headers.append("X-Proxy-Error",v);
+					}
+				}
+			}
 			if q.fallback.is_some(){
 				headers.append("Content-Type","image/png".parse().unwrap());
 				return Err((axum::http::StatusCode::OK,headers,(*dummy_img).clone()).into_response());
@@ -289,6 +512,9 @@ async fn get_file(
 			return Err((axum::http::StatusCode::BAD_REQUEST,headers,format!("{:?}",e)).into_response())
 		}
 	};
+	if let Ok(url)=resp.url().as_str().parse(){
+		headers.append("X-Remote-Final-Url",url);
+	}
 	fn add_remote_header(key:&'static str,headers:&mut HeaderMap,remote_headers:&reqwest::header::HeaderMap){
 		for v in remote_headers.get_all(key){
 			headers.append(key,String::from_utf8_lossy(v.as_bytes()).parse().unwrap());
@@ -308,18 +534,19 @@ async fn get_file(
 		add_remote_header("Content-Range",&mut headers,remote_headers);
 		add_remote_header("Accept-Ranges",&mut headers,remote_headers);
 	}
-	let mut is_accept_avif=false;
-	if !config.encode_avif{
-		//force no avif
-	}else if let Some(accept)=client_headers.get("Accept"){
-		if let Ok(accept)=std::str::from_utf8(accept.as_bytes()){
-			for e in accept.split(","){
-				if e=="image/avif"{
-					is_accept_avif=true;
-				}
-			}
-		}
-	}
+	let accept:Vec<&str>=client_headers.get_all("Accept").iter().filter_map(|v|v.to_str().ok()).collect();
+	let accept=if accept.is_empty(){
+		None
+	}else{
+		Some(accept.join(","))
+	};
+	let output_formats=negotiate::negotiate(accept.as_deref(),&output_chain);
+	let accept_encoding:Vec<&str>=client_headers.get_all("Accept-Encoding").iter().filter_map(|v|v.to_str().ok()).collect();
+	let accept_encoding=if accept_encoding.is_empty(){
+		None
+	}else{
+		Some(accept_encoding.join(","))
+	};
 	headers.append("Cache-Control","max-age=300".parse().unwrap());
 	for line in config.append_headers.iter(){
 		if let Some(idx)=line.find(":"){
@@ -334,27 +561,59 @@ async fn get_file(
 		}
 	}
 	RequestContext{
-		is_accept_avif,
+		output_formats,
+		vary_accept:output_chain.iter().any(|f|*f!=negotiate::OutputFormat::Legacy),
+		accept_encoding,
 		headers,
 		parms:q,
-		src_bytes:Vec::new(),
+		src_bytes:Default::default(),
 		config,
 		codec:Err(None),
 		dummy_img,
-		fontdb,
+		fonts,
+		job_queue,
+		memory_budget,
+		reservation:Default::default(),
+		deadline,
+		media_policy,
+		dpr:1f32,
 	}.encode(resp,is_img).await
 }
 struct RequestContext{
-	is_accept_avif:bool,
+	//クライアントが受け付ける出力形式を優先順に並べたもの
+	output_formats:Vec<negotiate::OutputFormat>,
+	//出力形式がAcceptで変わる場合、変換した応答にのみVary: Acceptを付ける
+	vary_accept:bool,
+	//そのまま転送する場合にクライアントが展開できる圧縮
+	accept_encoding:Option<String>,
 	headers:HeaderMap,
 	parms:RequestParams,
-	src_bytes:Vec<u8>,
+	src_bytes:spill::SrcBytes,
 	config:Arc<ConfigFile>,
 	codec:Result<image::ImageFormat,Option<image::ImageError>>,
 	dummy_img:Arc<Vec<u8>>,
-	fontdb:Arc<resvg::usvg::fontdb::Database>,
+	fonts:Arc<fonts::Fonts>,
+	job_queue:Arc<job_queue::JobQueue>,
+	memory_budget:Arc<memory_budget::MemoryBudget>,
+	reservation:memory_budget::Reservation,
+	deadline:tokio::time::Instant,
+	media_policy:Arc<media_policy::MediaPolicy>,
+	//SVGを描画する場合の倍率
+	dpr:f32,
 }
 impl RequestContext{
+	pub fn disposition_attachment(headers:&mut HeaderMap){
+		let k="Content-Disposition";
+		//disposition_extでinlineに書き換えた後のファイル名を引き継ぐ
+		let content_disposition=match headers.get(k).and_then(|cd|cd.to_str().ok()).and_then(|cd|cd.strip_prefix("inline")){
+			Some(params)=>format!("attachment{}",params),
+			None=>"attachment".to_owned(),
+		};
+		headers.remove(k);
+		if let Ok(v)=content_disposition.parse(){
+			headers.append(k,v);
+		}
+	}
 	pub fn disposition_ext(headers:&mut HeaderMap,ext:&str){
 		let k="Content-Disposition";
 		if let Some(cd)=headers.get(k){
@@ -394,61 +653,81 @@ impl RequestContext{
 }
 impl RequestContext{
 	async fn encode(mut self,resp: reqwest::Response,mut is_img:bool)->Result<(axum::http::StatusCode,HeaderMap,axum::body::Body),axum::response::Response>{
-		let mut is_svg=false;
-		let mut content_type=None;
-		if let Some(media)=self.headers.get("Content-Type"){
-			let s=String::from_utf8_lossy(media.as_bytes());
-			if s.as_ref()=="image/svg+xml"{
-				is_svg=true;
-			}else{
-				content_type=Some(s);
+		let content_type=self.headers.get("Content-Type").map(|media|String::from_utf8_lossy(media.as_bytes()).into_owned());
+		let mut is_svg=content_type.as_deref().map(|s|sniff::essence(s)=="image/svg+xml").unwrap_or(false);
+		let status=resp.status();
+		let encoding_header=resp.headers().get("Content-Encoding").cloned();
+		let encoding=content_encoding::ContentEncoding::from_header(encoding_header.as_ref());
+		let sniff_window=self.config.sniff_window.unwrap_or(4096);
+		let is_head=Self::is_head_range(&self.headers);
+		let is_identity=matches!(encoding,Ok(None));
+		let sniff_type=content_type.clone();
+		//判定が決まった時点で読むのをやめる
+		let is_decided=move|head:&[u8]|{
+			//途中からのRangeは先頭で判定しない
+			if !is_head{
+				return true;
 			}
+			//圧縮されている場合は展開しないと判定できないのでsniff_windowまで読む
+			if !is_identity{
+				return false;
+			}
+			let content_type=sniff_type.as_deref();
+			sniff::detect(head,content_type).is_some()||content_type.and_then(|s|crate::browsersafe::sniff_matches(&sniff::essence(s),head))==Some(true)
+		};
+		let resp=match tokio::time::timeout_at(self.deadline,PreDataStream::new(resp,sniff_window,is_decided)).await{
+			Ok(resp)=>resp,
+			Err(_)=>{
+				self.headers.append("X-Proxy-Error","Timeout".parse().unwrap());
+				return Err((axum::http::StatusCode::GATEWAY_TIMEOUT,self.headers.clone()).into_response());
+			}
+		};
+		//圧縮されている場合は展開した先頭部分で判定する
+		let head=match (&encoding,resp.head.as_ref()){
+			(Ok(Some(encoding)),Some(Ok(head)))=>Some(encoding.decode_head(head.clone(),sniff_window).await),
+			(Ok(None),Some(Ok(head)))=>Some(head.clone()),
+			_=>None,
+		};
+		if let Err(e)=&encoding{
+			//展開できないものは変換せずにそのまま転送する
+			println!("passthrough {}\t{}",e,self.parms.url);
+			is_svg=false;
+			is_img=false;
 		}
-		let status=resp.status();
-		let resp=PreDataStream::new(resp).await;
-		if let Some(Ok(head))=resp.head.as_ref(){
-			//utf8にパースできて空白文字を削除した後の先頭部分が<svgの場合はsvg
-			if std::str::from_utf8(&head).map(|s|s.trim().starts_with("<svg")).unwrap_or(false){
-				is_svg=true;
-			}else{
-				self.codec=image::guess_format(head).map_err(|e|Some(e));
-				if self.codec.is_err(){
-					if let Some(content_type)=content_type.as_ref(){
-						match content_type.as_ref(){
-							"image/x-targa"|"image/x-tga"=>self.codec=Ok(image::ImageFormat::Tga),
-							_=>{}
-						}
-					}
-					if head.starts_with(&[0xFF,0x0A])||head.starts_with(&[0x00,0x00,0x00,0x0C,0x4A,0x58,0x4C,0x20,0x0D,0x0A,0x87,0x0A]){
-						is_img=true;
-						self.headers.remove("Content-Type");
-						self.headers.append("Content-Type", "image/jxl".parse().unwrap());
-					}
-					if head.starts_with(&[0xFF,0x4F,0xFF,0x51])||head.starts_with(&[0x00,0x00,0x00,0x0C,0x6A,0x50,0x20,0x20,0x0D,0x0A,0x87,0x0A]){
-						is_img=true;
-						self.headers.remove("Content-Type");
-						self.headers.append("Content-Type", "image/jp2".parse().unwrap());
-					}
-					if head.starts_with(&[0x49,0x49,0xBC]){
-						is_img=true;
-						self.headers.remove("Content-Type");
-						self.headers.append("Content-Type", "image/jxr".parse().unwrap());
-					}
-				}
+		if let Some(head)=head.as_ref(){
+			//先頭のバイト列で判定できた形式はContent-Typeより優先する
+			match sniff::detect(head,content_type.as_deref()){
+				Some(sniff::Detected::Svg)=>is_svg=true,
+				Some(sniff::Detected::Image(format))=>{
+					is_svg=false;
+					self.codec=Ok(format);
+				},
+				Some(detected)=>{
+					//image crate以外でデコードする形式はContent-Typeで振り分ける
+					is_svg=false;
+					is_img=true;
+					self.headers.remove("Content-Type");
+					self.headers.append("Content-Type",detected.mime().parse().unwrap());
+				},
+				None=>{},
 			}
 		}
+		let resp=match encoding{
+			Ok(Some(encoding)) if is_svg||is_img||self.codec.is_ok()=>resp.decode(encoding),
+			_=>resp,
+		};
 		if is_svg{
 			self.load_all(resp).await?;
-			if let Ok(img)=self.encode_svg(self.fontdb.clone()){
-				self.headers.remove("Content-Length");
-				self.headers.remove("Content-Range");
-				self.headers.remove("Accept-Ranges");
+			self.decompress_svgz().await?;
+			if self.parms.emoji.is_some()&&self.config.emoji_svg_output.unwrap_or(false){
 				self.headers.remove("Cache-Control");
 				self.headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
-				return Err(self.response_img(img));
-			}else{
-				return Err((axum::http::StatusCode::OK,self.headers.clone(),self.src_bytes.clone()).into_response());
+				return Err(self.response_sanitized_svg());
 			}
+			self.headers.remove("Content-Length");
+			self.headers.remove("Content-Range");
+			self.headers.remove("Accept-Ranges");
+			return Err(self.render_svg().await);
 		}else if is_img||self.codec.is_ok(){
 			self.headers.remove("Content-Length");
 			self.headers.remove("Content-Range");
@@ -457,8 +736,24 @@ impl RequestContext{
 			let dummy_img=self.dummy_img.clone();
 			let is_fallback=self.parms.fallback.is_some();
 			let mut header=self.headers.clone();
+			let permit=match self.admit().await{
+				Ok(permit)=>permit,
+				Err(e)=>{
+					header.append("X-Proxy-Error",e.parse().unwrap());
+					return Err(if is_fallback{
+						header.remove("Content-Type");
+						header.append("Content-Type","image/png".parse().unwrap());
+						(axum::http::StatusCode::OK,header,(*dummy_img).clone()).into_response()
+					}else{
+						header.append("Retry-After","1".parse().unwrap());
+						(axum::http::StatusCode::SERVICE_UNAVAILABLE,header).into_response()
+					});
+				}
+			};
 			let mut handle=self;
 			let resp=if let Ok(resp)=tokio::runtime::Handle::current().spawn_blocking(move ||{
+				//処理が終わるまで枠を保持する
+				let _permit=permit;
 				let resp=handle.encode_img();
 				resp
 			}).await{
@@ -484,17 +779,80 @@ impl RequestContext{
 			}
 			return Err(resp);
 		}
+		let mut type_max_size=None;
 		if let Some(media)=self.headers.get("Content-Type"){
 			let s=String::from_utf8_lossy(media.as_bytes());
-			if crate::browsersafe::FILE_TYPE_BROWSERSAFE.contains(&s.as_ref()){
-
+			let media=s.split(';').next().unwrap_or_default().trim().to_lowercase();
+			if self.media_policy.is_denied(&media){
+				self.headers.append("X-Proxy-Error",format!("DeniedType:{}",media).parse().unwrap());
+				return Err(if self.parms.fallback.is_some(){
+					self.headers.remove("Content-Type");
+					self.headers.append("Content-Type","image/png".parse().unwrap());
+					(axum::http::StatusCode::OK,self.headers.clone(),(*self.dummy_img).clone()).into_response()
+				}else{
+					(axum::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,self.headers.clone()).into_response()
+				});
+			}
+			type_max_size=self.media_policy.max_size(&media);
+			let is_safe=if !self.media_policy.is_allowed(&media){
+				false
+			}else if is_head{
+				match head.as_ref(){
+					Some(head)=>crate::browsersafe::sniff_matches(&media,head).unwrap_or(true),
+					//展開できない場合は確認できない
+					None=>encoding.is_ok(),
+				}
 			}else{
+				true
+			};
+			if !is_safe{
+				println!("unsafe media {}\t{}",media,self.parms.url);
 				self.headers.remove("Content-Type");
-				self.headers.append("Content-Type","octet-stream".parse().unwrap());
+				self.headers.append("Content-Type",self.media_policy.fallback_type().parse().unwrap());
 				Self::disposition_ext(&mut self.headers,".unknown");
+				if self.media_policy.fallback_attachment(){
+					Self::disposition_attachment(&mut self.headers);
+				}
+			}
+		}
+		//無害化していないSVGをそのまま転送する場合もスクリプト等を実行させない
+		let is_svg_type=self.headers.get("Content-Type").map(|media|sniff::essence(&String::from_utf8_lossy(media.as_bytes()))=="image/svg+xml").unwrap_or(false);
+		if is_svg_type{
+			self.headers.remove("Content-Security-Policy");
+			self.headers.append("Content-Security-Policy",crate::svg_sanitize::SVG_CSP.parse().unwrap());
+		}
+		let limits=passthrough::PassthroughLimits::new(&self.config,type_max_size);
+		if let Some(len)=resp.content_length{
+			if let Err(e)=limits.check_length(len){
+				self.headers.append("X-Proxy-Error",e.parse().unwrap());
+				return Err(if self.parms.fallback.is_some(){
+					self.headers.remove("Content-Type");
+					self.headers.remove("Content-Length");
+					self.headers.remove("Content-Range");
+					self.headers.append("Content-Type","image/png".parse().unwrap());
+					(axum::http::StatusCode::OK,self.headers.clone(),(*self.dummy_img).clone()).into_response()
+				}else{
+					(axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response()
+				});
 			}
 		}
-		let body=axum::body::Body::from_stream(resp);
+		//クライアントが受け付ける場合は圧縮されたまま転送し、受け付けない場合は展開して転送する
+		if let Ok(Some(_))=encoding{
+			self.headers.append("Vary","Accept-Encoding".parse().unwrap());
+		}
+		let resp=match encoding{
+			Ok(Some(encoding)) if !encoding.is_accepted(self.accept_encoding.as_deref())=>{
+				self.headers.remove("Content-Length");
+				resp.decode(encoding)
+			},
+			_=>{
+				if let Some(encoding)=encoding_header{
+					self.headers.append("Content-Encoding",encoding);
+				}
+				resp
+			}
+		};
+		let body=axum::body::Body::from_stream(passthrough::LimitedStream::new(resp,limits,self.parms.url.clone()));
 		if status.is_success(){
 			self.headers.remove("Cache-Control");
 			self.headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
@@ -523,21 +881,82 @@ impl RequestContext{
 			})
 		}
 	}
+	//途中からのRangeは先頭を確認できないのでnosniffに任せる
+	fn is_head_range(headers:&HeaderMap)->bool{
+		match headers.get("Content-Range"){
+			Some(range)=>String::from_utf8_lossy(range.as_bytes()).trim_start_matches("bytes").trim_start().starts_with("0-"),
+			None=>true,
+		}
+	}
+	//画像処理の枠とデコード後の画像の分のメモリを確保する
+	async fn admit(&mut self)->Result<job_queue::JobPermit,String>{
+		let job_queue=self.job_queue.clone();
+		let memory_budget=self.memory_budget.clone();
+		println!("image queue running:{} waiting:{}\tmemory used:{} limit:{}",job_queue.running(),job_queue.waiting(),memory_budget.used(),memory_budget.limit());
+		let permit=job_queue.acquire().await.inspect_err(|e|{
+			println!("image queue {} running:{} waiting:{}",e,job_queue.running(),job_queue.waiting());
+		})?;
+		let decoded_size=self.decoded_size_hint();
+		memory_budget.reserve(&mut self.reservation,decoded_size).await.inspect_err(|e|{
+			println!("{}\tmemory used:{} limit:{}",e,memory_budget.used(),memory_budget.limit());
+		})?;
+		Ok(permit)
+	}
+	fn memory_budget_error(&mut self,e:String)->axum::response::Response{
+		println!("{}\tmemory used:{} limit:{}",e,self.memory_budget.used(),self.memory_budget.limit());
+		self.headers.append("X-Proxy-Error",e.parse().unwrap());
+		if self.parms.fallback.is_some(){
+			self.headers.remove("Content-Type");
+			self.headers.append("Content-Type","image/png".parse().unwrap());
+			return (axum::http::StatusCode::OK,self.headers.clone(),(*self.dummy_img).clone()).into_response();
+		}
+		self.headers.append("Retry-After","1".parse().unwrap());
+		(axum::http::StatusCode::SERVICE_UNAVAILABLE,self.headers.clone()).into_response()
+	}
 	async fn load_all(&mut self,mut resp: PreDataStream)->Result<(),axum::response::Response>{
 		let len_hint=resp.content_length.unwrap_or(2048.min(self.config.max_size));
 		if len_hint>self.config.max_size{
 			self.headers.append("X-Proxy-Error",format!("lengthHint:{}>{}",len_hint,self.config.max_size).parse().unwrap());
 			return Err((axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response())
 		}
-		let mut response_bytes=Vec::with_capacity(len_hint as usize);
-		while let Some(x) = resp.next().await{
+		//しきい値を超える分はメモリではなく一時ファイルに置く
+		let spill_threshold=self.config.spill_threshold.unwrap_or(16*1024*1024).min(self.config.max_size);
+		let memory_budget=self.memory_budget.clone();
+		if let Err(e)=memory_budget.reserve(&mut self.reservation,len_hint.min(spill_threshold)).await{
+			return Err(self.memory_budget_error(e));
+		}
+		let mut response_bytes=spill::SpillBuffer::new(spill_threshold as usize,self.config.spill_dir.as_ref().map(|s|s.into()),len_hint as usize);
+		loop{
+			let x=match tokio::time::timeout_at(self.deadline,resp.next()).await{
+				Ok(Some(x))=>x,
+				Ok(None)=>break,
+				Err(_)=>{
+					self.headers.append("X-Proxy-Error","LoadAll:Timeout".parse().unwrap());
+					return Err((axum::http::StatusCode::GATEWAY_TIMEOUT,self.headers.clone()).into_response())
+				}
+			};
 			match x{
 				Ok(b)=>{
 					if response_bytes.len()+b.len()>self.config.max_size as usize{
 						self.headers.append("X-Proxy-Error",format!("length:{}>{}",response_bytes.len()+b.len(),self.config.max_size).parse().unwrap());
 						return Err((axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response())
 					}
-					response_bytes.extend_from_slice(&b);
+					let len=(response_bytes.len()+b.len()) as u64;
+					if len<=spill_threshold&&len>self.reservation.bytes(){
+						//Vecと同じように倍々で予約を増やす
+						let grow=len.max(self.reservation.bytes()*2).min(spill_threshold)-self.reservation.bytes();
+						if let Err(e)=memory_budget.reserve(&mut self.reservation,grow).await{
+							return Err(self.memory_budget_error(e));
+						}
+					}
+					if let Err(e)=response_bytes.push(&b).await{
+						self.headers.append("X-Proxy-Error",format!("Spill:{:?}",e).parse().unwrap());
+						return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR,self.headers.clone()).into_response())
+					}
+					if response_bytes.is_spilled()&&self.reservation.bytes()>0{
+						//一時ファイルに移した分の予約は不要
+						self.reservation=Default::default();
+					}
 				},
 				Err(e)=>{
 					self.headers.append("X-Proxy-Error",format!("LoadAll:{:?}",e).parse().unwrap());
@@ -545,29 +964,72 @@ impl RequestContext{
 				}
 			}
 		}
-		self.src_bytes=response_bytes;
+		self.src_bytes=match response_bytes.finish().await{
+			Ok(src_bytes)=>src_bytes,
+			Err(e)=>{
+				self.headers.append("X-Proxy-Error",format!("Spill:{:?}",e).parse().unwrap());
+				return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR,self.headers.clone()).into_response())
+			}
+		};
 		Ok(())
 	}
 }
 struct PreDataStream{
 	content_length:Option<u64>,
-	head:Option<Result<axum::body::Bytes, reqwest::Error>>,
-	last:Pin<Box<dyn futures::stream::Stream<Item=Result<axum::body::Bytes, reqwest::Error>>+Send+Sync>>,
+	head:Option<std::io::Result<axum::body::Bytes>>,
+	last:content_encoding::ByteStream,
 }
 impl  PreDataStream{
-	async fn new(value: reqwest::Response) -> Self {
+	async fn new(value: reqwest::Response,sniff_window:usize,is_decided:impl Fn(&[u8])->bool) -> Self {
 		let content_length=value.content_length();
-		let mut stream=value.bytes_stream();
-		let head=stream.next().await;
+		let mut stream=value.bytes_stream().map(|r|r.map_err(std::io::Error::other));
+		//最初のチャンクが短い場合があるので判定に必要な長さが揃うか、判定が決まるまで読む
+		let mut head=Vec::new();
+		let mut error=None;
+		while head.len()<sniff_window.max(1){
+			match stream.next().await{
+				Some(Ok(b))=>{
+					head.extend_from_slice(&b);
+					if is_decided(&head){
+						break;
+					}
+				},
+				Some(Err(e))=>{
+					error=Some(e);
+					break;
+				},
+				None=>break,
+			}
+		}
+		if head.is_empty(){
+			return Self{
+				content_length,
+				head:error.map(Err),
+				last: Box::pin(stream)
+			};
+		}
+		//読み込み中のエラーは先頭部分の後に返す
+		let last:content_encoding::ByteStream=match error{
+			Some(e)=>Box::pin(futures::stream::iter([Err(e)]).chain(stream)),
+			None=>Box::pin(stream),
+		};
 		Self{
 			content_length,
-			head,
-			last: Box::pin(stream)
+			head:Some(Ok(head.into())),
+			last
+		}
+	}
+	//本文をContent-Encodingに従って展開する
+	fn decode(self,encoding:content_encoding::ContentEncoding)->Self{
+		Self{
+			content_length:None,
+			head:None,
+			last:encoding.decode(self),
 		}
 	}
 }
 impl futures::stream::Stream for PreDataStream{
-	type Item=Result<axum::body::Bytes, reqwest::Error>;
+	type Item=std::io::Result<axum::body::Bytes>;
 
 	fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
 		let mut r=self.as_mut();
@@ -576,4 +1038,137 @@ impl futures::stream::Stream for PreDataStream{
 		}
 		r.last.as_mut().poll_next(cx)
 	}
-}
\ No newline at end of file
+}
+//127.0.0.1のスタブから取得するget_fileの引数
+#[cfg(test)]
+fn test_state()->AppState{
+	let mut config=test_config();
+	config.allowed_networks=Some(vec!["127.0.0.1/32".to_owned()]);
+	let config=Arc::new(config);
+	let host_policy=Arc::new(host_policy::HostPolicy::new(None,None,None).unwrap());
+	let policy=Arc::new(ip_policy::IpPolicy::new(config.allowed_networks.as_ref(),None).unwrap());
+	let lookup=resolver::HickoryLookup::new(Some(&vec!["127.0.0.1:9".to_owned()]),std::time::Duration::from_millis(100),None,None,DnsIpStrategy::Ipv4ThenIpv6.into()).unwrap();
+	let resolver=resolver::PinnedResolver::new(policy,Arc::new(lookup),std::time::Duration::from_secs(10));
+	let client=reqwest::Client::builder().dns_resolver(Arc::new(resolver.clone())).redirect(resolver.redirect_policy(host_policy.clone(),10)).build().unwrap();
+	(
+		client,
+		config.clone(),
+		Arc::new(include_bytes!("../asset/dummy.png").to_vec()),
+		Arc::new(fonts::Fonts::new(resvg::usvg::fontdb::Database::new(),&config)),
+		resolver,
+		host_policy,
+		Arc::new(rate_limit::RateLimit::new(None,None,None,None,None).unwrap()),
+		Arc::new(job_queue::JobQueue::new(1,1,std::time::Duration::from_secs(1))),
+		Arc::new(memory_budget::MemoryBudget::new(1024*1024,std::time::Duration::from_secs(1))),
+		Arc::new(media_policy::MediaPolicy::new(&config).unwrap()),
+	)
+}
+#[test]
+fn pre_data_stream_decided(){
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	//先頭のチャンクの後は届かない応答
+	let response=||{
+		let stream=futures::stream::iter([Ok::<_,std::io::Error>(axum::body::Bytes::from_static(b"\x89PNG\r\n\x1a\n\0\0"))]).chain(futures::stream::pending());
+		reqwest::Response::from(axum::http::Response::new(reqwest::Body::wrap_stream(stream)))
+	};
+	rt.block_on(async{
+		let resp=tokio::time::timeout(std::time::Duration::from_millis(500),PreDataStream::new(response(),4096,|head|sniff::detect(head,None).is_some())).await.unwrap();
+		assert_eq!(resp.head.unwrap().unwrap().len(),10);
+		//判定できない場合はsniff_windowまで待つ
+		assert!(tokio::time::timeout(std::time::Duration::from_millis(100),PreDataStream::new(response(),4096,|_|false)).await.is_err());
+	});
+}
+#[test]
+fn passthrough_content_encoding(){
+	use std::io::{BufRead, BufReader, Write};
+	use tokio::io::AsyncWriteExt;
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let body=b"hello ".repeat(100);
+	let br=rt.block_on(async{
+		let mut br=async_compression::tokio::write::BrotliEncoder::new(Vec::new());
+		br.write_all(&body).await.unwrap();
+		br.shutdown().await.unwrap();
+		br.into_inner()
+	});
+	//Accept-Encodingに関わらずbrで返すスタブ
+	let listener=std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr=listener.local_addr().unwrap();
+	let served=br.clone();
+	std::thread::spawn(move||{
+		for stream in listener.incoming(){
+			let mut stream=match stream{
+				Ok(stream)=>stream,
+				Err(_)=>break,
+			};
+			let mut reader=BufReader::new(&stream);
+			loop{
+				let mut header=String::new();
+				if reader.read_line(&mut header).unwrap()==0||header=="\r\n"{
+					break;
+				}
+			}
+			write!(stream,"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: br\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",served.len()).unwrap();
+			stream.write_all(&served).unwrap();
+		}
+	});
+	let arg_tup=test_state();
+	let get=|accept_encoding:&'static str|{
+		let mut client_headers=HeaderMap::new();
+		client_headers.append("Accept-Encoding",accept_encoding.parse().unwrap());
+		let uri:axum::http::Uri=format!("/?url=http://{}/a.txt",addr).parse().unwrap();
+		let q=axum::extract::Query::<RequestParams>::try_from_uri(&uri).unwrap();
+		get_file(None,axum::extract::ConnectInfo(addr),client_headers,arg_tup.clone(),q)
+	};
+	rt.block_on(async{
+		//brを受け付けないクライアントには展開して返す
+		let (_,headers,resp)=get("gzip").await.unwrap();
+		assert!(headers.get("Content-Encoding").is_none());
+		assert!(headers.get("Content-Length").is_none());
+		assert_eq!(axum::body::to_bytes(resp,usize::MAX).await.unwrap(),body);
+		//受け付ける場合は圧縮されたまま返す
+		let (_,headers,resp)=get("gzip, br").await.unwrap();
+		assert_eq!(headers.get("Content-Encoding").unwrap(),"br");
+		assert_eq!(axum::body::to_bytes(resp,usize::MAX).await.unwrap(),br);
+	});
+}
+#[test]
+fn passthrough_svg_csp(){
+	use std::io::{BufRead, BufReader, Write};
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let listener=std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr=listener.local_addr().unwrap();
+	std::thread::spawn(move||{
+		for stream in listener.incoming(){
+			let mut stream=match stream{
+				Ok(stream)=>stream,
+				Err(_)=>break,
+			};
+			let mut reader=BufReader::new(&stream);
+			loop{
+				let mut header=String::new();
+				if reader.read_line(&mut header).unwrap()==0||header=="\r\n"{
+					break;
+				}
+			}
+			let body=b"<svg xmlns=\"http://www.w3.org/2000/svg\" onload=\"alert(1)\"/>";
+			write!(stream,"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: compress\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",body.len()).unwrap();
+			stream.write_all(body).unwrap();
+		}
+	});
+	let uri:axum::http::Uri=format!("/?url=http://{}/a.html",addr).parse().unwrap();
+	let q=axum::extract::Query::<RequestParams>::try_from_uri(&uri).unwrap();
+	let mut state=test_state();
+	//確認できない形式を無害化していないSVGとして転送する設定
+	let mut config=test_config();
+	config.passthrough_fallback_type=Some("image/svg+xml".to_owned());
+	config.passthrough_fallback_disposition=Some("inline".to_owned());
+	state.9=Arc::new(media_policy::MediaPolicy::new(&config).unwrap());
+	let res=rt.block_on(get_file(None,axum::extract::ConnectInfo(addr),HeaderMap::new(),state,q));
+	let headers=match &res{
+		Ok((_,headers,_))=>headers,
+		Err(resp)=>resp.headers(),
+	};
+	assert_eq!(headers.get("Content-Security-Policy").unwrap(),crate::svg_sanitize::SVG_CSP);
+	assert_eq!(headers.get("Content-Type").unwrap(),"image/svg+xml");
+	assert_eq!(headers.get("X-Content-Type-Options").unwrap(),"nosniff");
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use crate::ConfigFile;
+
+//画像以外をそのまま転送する形式の設定
+//"video/*"のように書くとその種類のすべての形式に一致する
+pub(crate) struct MediaPolicy{
+	allowed_types:Vec<String>,
+	denied_types:Vec<String>,
+	type_max_size:HashMap<String,u64>,
+	fallback_type:String,
+	fallback_attachment:bool,
+}
+impl MediaPolicy{
+	pub fn new(config:&ConfigFile)->Result<Self,String>{
+		let normalize=|v:&Vec<String>|v.iter().map(|s|s.trim().to_lowercase()).filter(|s|!s.is_empty()).collect::<Vec<_>>();
+		let allowed_types=match &config.passthrough_allowed_types{
+			Some(v)=>normalize(v),
+			None=>crate::browsersafe::FILE_TYPE_BROWSERSAFE.iter().map(|s|s.to_string()).collect(),
+		};
+		let denied_types=config.passthrough_denied_types.as_ref().map(normalize).unwrap_or_default();
+		let type_max_size=config.passthrough_type_max_size.iter().flatten().map(|(k,v)|(k.trim().to_lowercase(),*v)).collect();
+		let fallback_type=config.passthrough_fallback_type.clone().unwrap_or_else(||"application/octet-stream".to_owned());
+		if axum::http::HeaderValue::from_str(&fallback_type).is_err(){
+			return Err(format!("invalid passthrough_fallback_type: {}",fallback_type));
+		}
+		let fallback_attachment=match config.passthrough_fallback_disposition.as_deref(){
+			None|Some("attachment")=>true,
+			Some("inline")=>false,
+			Some(s)=>return Err(format!("invalid passthrough_fallback_disposition: {}",s)),
+		};
+		Ok(Self{
+			allowed_types,
+			denied_types,
+			type_max_size,
+			fallback_type,
+			fallback_attachment,
+		})
+	}
+	pub fn is_denied(&self,media:&str)->bool{
+		self.denied_types.iter().any(|pattern|type_matches(pattern,media))
+	}
+	pub fn is_allowed(&self,media:&str)->bool{
+		self.allowed_types.iter().any(|pattern|type_matches(pattern,media))
+	}
+	pub fn max_size(&self,media:&str)->Option<u64>{
+		if let Some(max_size)=self.type_max_size.get(media){
+			return Some(*max_size);
+		}
+		self.type_max_size.iter().filter(|(pattern,_)|type_matches(pattern,media)).map(|(_,v)|*v).min()
+	}
+	pub fn fallback_type(&self)->&str{
+		&self.fallback_type
+	}
+	pub fn fallback_attachment(&self)->bool{
+		self.fallback_attachment
+	}
+}
+fn type_matches(pattern:&str,media:&str)->bool{
+	if pattern==media||pattern=="*/*"{
+		return true;
+	}
+	match pattern.strip_suffix("/*"){
+		Some(main_type)=>media.split('/').next()==Some(main_type),
+		None=>false,
+	}
+}
+#[test]
+fn media_policy_config(){
+	let mut config=crate::test_config();
+	let policy=MediaPolicy::new(&config).unwrap();
+	assert!(policy.is_allowed("video/mp4"));
+	assert!(!policy.is_allowed("text/html"));
+	assert!(!policy.is_denied("text/html"));
+	assert_eq!(policy.fallback_type(),"application/octet-stream");
+	config.passthrough_allowed_types=Some(vec!["video/*".to_owned(),"Application/PDF".to_owned()]);
+	config.passthrough_denied_types=Some(vec!["text/*".to_owned()]);
+	config.passthrough_type_max_size=Some([("video/*".to_owned(),100),("video/mp4".to_owned(),200)].into_iter().collect());
+	config.passthrough_fallback_disposition=Some("inline".to_owned());
+	let policy=MediaPolicy::new(&config).unwrap();
+	assert!(policy.is_allowed("video/x-matroska"));
+	assert!(policy.is_allowed("application/pdf"));
+	assert!(!policy.is_allowed("audio/ogg"));
+	assert!(policy.is_denied("text/html"));
+	assert_eq!(policy.max_size("video/mp4"),Some(200));
+	assert_eq!(policy.max_size("video/webm"),Some(100));
+	assert_eq!(policy.max_size("audio/ogg"),None);
+	assert!(!policy.fallback_attachment());
+	config.passthrough_fallback_disposition=Some("download".to_owned());
+	assert!(MediaPolicy::new(&config).is_err());
+}
@@ -0,0 +1,76 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+//プロセス全体で取得中のデータとデコード後の画像に使うメモリ量を制限する
+//1permit=1KiB
+pub(crate) struct MemoryBudget{
+	permits:Arc<Semaphore>,
+	limit_kib:usize,
+	wait_timeout:Duration,
+}
+//Dropで予約が解放される
+#[derive(Default)]
+pub(crate) struct Reservation{
+	permit:Option<OwnedSemaphorePermit>,
+}
+impl Reservation{
+	pub fn bytes(&self)->u64{
+		self.permit.as_ref().map(|p|p.num_permits() as u64*1024).unwrap_or(0)
+	}
+}
+impl MemoryBudget{
+	pub fn new(limit:u64,wait_timeout:Duration)->Self{
+		let limit_kib=(limit/1024).clamp(1,u32::MAX as u64) as usize;
+		Self{
+			permits:Arc::new(Semaphore::new(limit_kib)),
+			limit_kib,
+			wait_timeout,
+		}
+	}
+	pub fn used(&self)->u64{
+		(self.limit_kib-self.permits.available_permits()) as u64*1024
+	}
+	pub fn limit(&self)->u64{
+		self.limit_kib as u64*1024
+	}
+	pub async fn reserve(&self,reservation:&mut Reservation,bytes:u64)->Result<(),String>{
+		let kib=bytes.div_ceil(1024);
+		let held=reservation.bytes()/1024;
+		//自分の予約と合わせて上限を超える場合は待っても確保できない
+		if held+kib>self.limit_kib as u64{
+			return Err(format!("MemoryBudget {}>{}",held*1024+bytes,self.limit()));
+		}
+		let permit=tokio::time::timeout(self.wait_timeout,self.permits.clone().acquire_many_owned(kib as u32)).await;
+		let permit=match permit{
+			Ok(Ok(permit))=>permit,
+			Ok(Err(_))=>return Err("MemoryBudgetClosed".to_owned()),
+			Err(_)=>return Err(format!("MemoryBudgetTimeout used:{} request:{}",self.used(),bytes)),
+		};
+		match reservation.permit.as_mut(){
+			Some(p)=>p.merge(permit),
+			None=>reservation.permit=Some(permit),
+		}
+		Ok(())
+	}
+}
+#[test]
+fn memory_budget_reserve(){
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let budget=MemoryBudget::new(1024*1024,Duration::from_millis(50));
+	rt.block_on(async{
+		let mut a=Reservation::default();
+		budget.reserve(&mut a,600*1024).await.unwrap();
+		budget.reserve(&mut a,100).await.unwrap();
+		assert_eq!(a.bytes(),601*1024);
+		assert_eq!(budget.used(),601*1024);
+		//上限を超える予約は待たずに失敗する
+		assert!(budget.reserve(&mut a,500*1024).await.unwrap_err().starts_with("MemoryBudget "));
+		let mut b=Reservation::default();
+		assert!(budget.reserve(&mut b,500*1024).await.unwrap_err().starts_with("MemoryBudgetTimeout"));
+		drop(a);
+		assert_eq!(budget.used(),0);
+		budget.reserve(&mut b,500*1024).await.unwrap();
+		assert_eq!(budget.used(),500*1024);
+	});
+}
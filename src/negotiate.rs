@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ConfigFile;
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="lowercase")]
+pub(crate) enum OutputFormat{
+	Jxl,
+	Avif,
+	Webp,
+	//PNG、JPEG、GIFのうち画像に合うもの
+	Legacy,
+}
+impl OutputFormat{
+	fn quality(&self,ranges:&[(String,f32)])->f32{
+		match self{
+			//新しい形式は明示されている場合のみ
+			Self::Jxl=>quality(ranges,"image/jxl",false),
+			Self::Avif=>quality(ranges,"image/avif",false),
+			Self::Webp=>quality(ranges,"image/webp",false),
+			Self::Legacy=>["image/png","image/jpeg","image/gif"].iter().map(|mime|quality(ranges,mime,true)).fold(0f32,f32::max),
+		}
+	}
+	//アニメーションを出力できる形式
+	pub fn is_animated(&self)->bool{
+		matches!(self,Self::Webp|Self::Legacy)
+	}
+}
+//設定された優先順からエンコードできないものを除く
+pub(crate) fn chain(config:&ConfigFile)->Vec<OutputFormat>{
+	let chain=config.output_formats.clone().unwrap_or_else(||vec![OutputFormat::Jxl,OutputFormat::Avif,OutputFormat::Webp,OutputFormat::Legacy]);
+	chain.into_iter().filter(|f|match f{
+		OutputFormat::Jxl=>is_jxl_enabled(config),
+		OutputFormat::Avif=>config.encode_avif,
+		_=>true,
+	}).collect()
+}
+pub(crate) fn is_jxl_enabled(config:&ConfigFile)->bool{
+	cfg!(feature="jxl-encoder")&&config.encode_jxl.unwrap_or(false)
+}
+//クライアントが受け付ける形式をqの大きい順、同じ場合は設定の順に並べる
+pub(crate) fn negotiate(accept:Option<&str>,chain:&[OutputFormat])->Vec<OutputFormat>{
+	//Acceptが無い場合は*/*として扱う
+	let ranges=accept.map(parse_accept).unwrap_or_else(||vec![("*/*".to_owned(),1f32)]);
+	let mut accepted:Vec<(OutputFormat,f32)>=chain.iter().map(|f|(*f,f.quality(&ranges))).filter(|(_,q)|*q>0f32).collect();
+	accepted.sort_by(|a,b|b.1.total_cmp(&a.1));
+	if accepted.is_empty(){
+		//どれも受け付けない場合は従来の形式で返す
+		return vec![OutputFormat::Legacy];
+	}
+	accepted.into_iter().map(|(f,_)|f).collect()
+}
+//アニメーションの出力形式、WebPを優先しない場合はGIF(legacy)にする
+pub(crate) fn anim_format(output_formats:&[OutputFormat])->OutputFormat{
+	match output_formats.iter().find(|f|f.is_animated()){
+		Some(OutputFormat::Webp)=>OutputFormat::Webp,
+		_=>OutputFormat::Legacy,
+	}
+}
+fn parse_accept(accept:&str)->Vec<(String,f32)>{
+	accept.split(',').filter_map(|e|{
+		let mut params=e.split(';');
+		let mime=params.next()?.trim().to_ascii_lowercase();
+		if mime.is_empty(){
+			return None;
+		}
+		let mut q=1f32;
+		for param in params{
+			if let Some((k,v))=param.split_once('='){
+				if k.trim().eq_ignore_ascii_case("q"){
+					//不正なqの要素は無視する
+					q=v.trim().parse::<f32>().ok().filter(|q|(0f32..=1f32).contains(q))?;
+				}
+			}
+		}
+		Some((mime,q))
+	}).collect()
+}
+//より具体的な指定を優先する
+fn quality(ranges:&[(String,f32)],mime:&str,wildcard:bool)->f32{
+	let main_type=mime.split('/').next().unwrap_or_default();
+	let mut best:Option<(u8,f32)>=None;
+	for (range,q) in ranges{
+		let specificity=if range==mime{
+			2
+		}else if wildcard&&range.strip_suffix("/*")==Some(main_type){
+			1
+		}else if wildcard&&range=="*/*"{
+			0
+		}else{
+			continue;
+		};
+		if best.map(|(s,_)|specificity>s).unwrap_or(true){
+			best=Some((specificity,*q));
+		}
+	}
+	best.map(|(_,q)|q).unwrap_or(0f32)
+}
+#[test]
+fn negotiate_accept(){
+	use OutputFormat::*;
+	let chain=[Avif,Webp,Legacy];
+	let chrome="image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+	assert_eq!(negotiate(Some(chrome),&chain),[Avif,Webp,Legacy]);
+	assert_eq!(negotiate(Some(" image/avif;q=0.9 , image/webp"),&chain),[Webp,Avif]);
+	assert_eq!(negotiate(Some("image/webp,*/*"),&chain),[Webp,Legacy]);
+	//ワイルドカードだけでは新しい形式は選ばない
+	assert_eq!(negotiate(Some("*/*"),&chain),[Legacy]);
+	assert_eq!(negotiate(None,&chain),[Legacy]);
+	assert_eq!(negotiate(Some("image/avif;q=0,image/webp;q=0.5,image/*;q=0.8"),&chain),[Legacy,Webp]);
+	assert_eq!(negotiate(Some("image/avif,image/png;q=0,*/*;q=0.5"),&chain),[Avif,Legacy]);
+	assert_eq!(negotiate(Some("image/avif;q=x,text/html"),&chain),[Legacy]);
+	assert_eq!(negotiate(Some("image/*;q=0"),&[Avif,Webp]),[Legacy]);
+	assert_eq!(negotiate(Some("image/avif,image/webp"),&[Webp]),[Webp]);
+	let safari="image/webp,image/avif,image/jxl,image/heic,image/heic-sequence,video/*;q=0.8,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5";
+	assert_eq!(negotiate(Some(safari),&[Jxl,Avif,Webp,Legacy]),[Jxl,Avif,Webp,Legacy]);
+	assert_eq!(negotiate(Some(chrome),&[Jxl,Avif,Webp,Legacy]),[Avif,Webp,Legacy]);
+	assert_eq!(anim_format(&negotiate(Some(chrome),&chain)),Webp);
+	assert_eq!(anim_format(&negotiate(Some("image/avif"),&chain)),Legacy);
+	assert_eq!(anim_format(&negotiate(Some("image/avif,image/gif;q=0.9,image/webp;q=0.5"),&chain)),Legacy);
+	assert_eq!(anim_format(&negotiate(Some("image/avif,image/webp"),&[Avif,Webp])),Webp);
+}
@@ -0,0 +1,148 @@
+use std::{future::Future, pin::Pin, task::{Context, Poll}, time::Duration};
+
+use axum::body::Bytes;
+use futures::Stream;
+use tokio::time::{Instant, Sleep};
+
+use crate::ConfigFile;
+
+//画像以外をそのまま転送する場合の制限
+pub(crate) struct PassthroughLimits{
+	max_size:u64,
+	idle_timeout:Duration,
+	max_duration:Option<Duration>,
+	bytes_per_sec:Option<u64>,
+}
+impl PassthroughLimits{
+	pub fn new(config:&ConfigFile,type_max_size:Option<u64>)->Self{
+		Self{
+			max_size:type_max_size.or(config.passthrough_max_size).unwrap_or(config.max_size),
+			idle_timeout:Duration::from_millis(config.passthrough_idle_timeout.unwrap_or(config.timeout)),
+			max_duration:config.passthrough_max_duration.map(Duration::from_millis),
+			bytes_per_sec:config.passthrough_rate_limit.filter(|r|*r>0),
+		}
+	}
+	pub fn check_length(&self,len:u64)->Result<(),String>{
+		if len>self.max_size{
+			return Err(format!("PassthroughLength:{}>{}",len,self.max_size));
+		}
+		Ok(())
+	}
+}
+pub(crate) struct LimitedStream<S>{
+	inner:Pin<Box<S>>,
+	limits:PassthroughLimits,
+	url:String,
+	sent:u64,
+	started:Instant,
+	idle:Pin<Box<Sleep>>,
+	deadline:Option<Pin<Box<Sleep>>>,
+	throttle:Option<Pin<Box<Sleep>>>,
+	done:bool,
+}
+impl<S> LimitedStream<S>{
+	pub fn new(inner:S,limits:PassthroughLimits,url:String)->Self{
+		let started=Instant::now();
+		Self{
+			inner:Box::pin(inner),
+			idle:Box::pin(tokio::time::sleep_until(started+limits.idle_timeout)),
+			deadline:limits.max_duration.map(|d|Box::pin(tokio::time::sleep_until(started+d))),
+			throttle:None,
+			limits,
+			url,
+			sent:0,
+			started,
+			done:false,
+		}
+	}
+	//途中で打ち切った事がクライアントに分かるようにエラーで終了する
+	fn abort(&mut self,e:String)->Poll<Option<std::io::Result<Bytes>>>{
+		println!("passthrough {}\t{}",e,self.url);
+		self.done=true;
+		Poll::Ready(Some(Err(std::io::Error::other(e))))
+	}
+}
+impl<S,E> Stream for LimitedStream<S> where S:Stream<Item=Result<Bytes,E>>,E:std::fmt::Debug{
+	type Item=std::io::Result<Bytes>;
+	fn poll_next(self:Pin<&mut Self>,cx:&mut Context<'_>)->Poll<Option<Self::Item>>{
+		let this=self.get_mut();
+		if this.done{
+			return Poll::Ready(None);
+		}
+		if let Some(deadline)=this.deadline.as_mut(){
+			if deadline.as_mut().poll(cx).is_ready(){
+				return this.abort(format!("PassthroughDuration:{}ms",this.started.elapsed().as_millis()));
+			}
+		}
+		if let Some(throttle)=this.throttle.as_mut(){
+			if throttle.as_mut().poll(cx).is_pending(){
+				return Poll::Pending;
+			}
+			this.throttle=None;
+			this.idle.as_mut().reset(Instant::now()+this.limits.idle_timeout);
+		}
+		match this.inner.as_mut().poll_next(cx){
+			Poll::Ready(Some(Ok(b)))=>{
+				this.sent+=b.len() as u64;
+				if this.sent>this.limits.max_size{
+					return this.abort(format!("PassthroughLength:{}>{}",this.sent,this.limits.max_size));
+				}
+				let now=Instant::now();
+				this.idle.as_mut().reset(now+this.limits.idle_timeout);
+				if let Some(bytes_per_sec)=this.limits.bytes_per_sec{
+					//送信量に応じて次のチャンクを読むまで待つ
+					let due=this.started+Duration::from_secs_f64(this.sent as f64/bytes_per_sec as f64);
+					if due>now{
+						this.throttle=Some(Box::pin(tokio::time::sleep_until(due)));
+					}
+				}
+				Poll::Ready(Some(Ok(b)))
+			},
+			Poll::Ready(Some(Err(e)))=>this.abort(format!("Passthrough:{:?}",e)),
+			Poll::Ready(None)=>{
+				this.done=true;
+				Poll::Ready(None)
+			},
+			Poll::Pending=>{
+				if this.idle.as_mut().poll(cx).is_ready(){
+					return this.abort(format!("PassthroughIdle:{}ms",this.limits.idle_timeout.as_millis()));
+				}
+				Poll::Pending
+			},
+		}
+	}
+}
+#[test]
+fn passthrough_limits(){
+	use futures::StreamExt;
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let limits=||PassthroughLimits{
+		max_size:10,
+		idle_timeout:Duration::from_millis(100),
+		max_duration:Some(Duration::from_secs(5)),
+		bytes_per_sec:None,
+	};
+	rt.block_on(async{
+		let chunks=vec![Ok::<_,()>(Bytes::from_static(b"01234")),Ok(Bytes::from_static(b"56789")),Ok(Bytes::from_static(b"a"))];
+		let mut s=LimitedStream::new(futures::stream::iter(chunks),limits(),String::new());
+		assert!(s.next().await.unwrap().is_ok());
+		assert!(s.next().await.unwrap().is_ok());
+		assert!(s.next().await.unwrap().is_err());
+		assert!(s.next().await.is_none());
+		//データが届かないまま100ms経つと打ち切る
+		let mut s=LimitedStream::new(futures::stream::pending::<Result<Bytes,()>>(),limits(),String::new());
+		let started=Instant::now();
+		assert!(s.next().await.unwrap().is_err());
+		assert!(started.elapsed()>=Duration::from_millis(100));
+		//100byte/sで10byte送ると100msかかる
+		let mut limits=limits();
+		limits.bytes_per_sec=Some(100);
+		let chunks=vec![Ok::<_,()>(Bytes::from_static(b"01234")),Ok(Bytes::from_static(b"56789"))];
+		let mut s=LimitedStream::new(futures::stream::iter(chunks),limits,String::new());
+		let started=Instant::now();
+		while let Some(b)=s.next().await{
+			assert!(b.is_ok());
+		}
+		assert!(started.elapsed()>=Duration::from_millis(100));
+	});
+}
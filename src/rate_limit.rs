@@ -0,0 +1,187 @@
+use std::{collections::HashMap, hash::Hash, net::{IpAddr, Ipv6Addr, SocketAddr}, sync::Mutex, time::{Duration, Instant}};
+
+use axum::http::HeaderMap;
+use ipnet::{Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+
+const MIN_PURGE_LEN:usize=4096;
+struct Bucket{
+	tokens:f64,
+	last:Instant,
+}
+struct TokenBuckets<K>{
+	rate:f64,
+	burst:f64,
+	buckets:Mutex<(HashMap<K,Bucket>,usize)>,
+}
+impl<K:Hash+Eq> TokenBuckets<K>{
+	fn new(rate:f64,burst:Option<u32>)->Result<Self,String>{
+		if rate.is_nan()||rate<=0f64{
+			return Err(format!("invalid rate limit: {}",rate));
+		}
+		//burstの指定が無い場合は10秒分
+		let burst=burst.map(|b|b as f64).unwrap_or(rate*10f64).max(1f64);
+		Ok(Self{
+			rate,
+			burst,
+			buckets:Mutex::new((HashMap::new(),MIN_PURGE_LEN)),
+		})
+	}
+	fn take(&self,key:K)->Result<(),Duration>{
+		let now=Instant::now();
+		let mut lock=self.buckets.lock().unwrap();
+		let (buckets,purge_len)=&mut *lock;
+		if buckets.len()>=*purge_len{
+			//満タンまで回復したバケットは新規作成と同じなので削除する
+			buckets.retain(|_,b|b.tokens+now.duration_since(b.last).as_secs_f64()*self.rate<self.burst);
+			*purge_len=MIN_PURGE_LEN.max(buckets.len()*2);
+		}
+		let bucket=buckets.entry(key).or_insert(Bucket{
+			tokens:self.burst,
+			last:now,
+		});
+		bucket.tokens=(bucket.tokens+now.duration_since(bucket.last).as_secs_f64()*self.rate).min(self.burst);
+		bucket.last=now;
+		if bucket.tokens>=1f64{
+			bucket.tokens-=1f64;
+			Ok(())
+		}else{
+			Err(Duration::from_secs_f64((1f64-bucket.tokens)/self.rate))
+		}
+	}
+}
+pub(crate) struct RateLimit{
+	client:Option<TokenBuckets<IpAddr>>,
+	host:Option<TokenBuckets<String>>,
+	trusted_v4:IpRange<Ipv4Net>,
+	trusted_v6:IpRange<Ipv6Net>,
+}
+impl RateLimit{
+	pub fn new(
+		client_rate:Option<f64>,
+		client_burst:Option<u32>,
+		host_rate:Option<f64>,
+		host_burst:Option<u32>,
+		trusted_proxies:Option<&Vec<String>>,
+	)->Result<Self,String>{
+		let mut trusted_v4=IpRange::new();
+		let mut trusted_v6=IpRange::new();
+		if let Some(trusted_proxies)=trusted_proxies{
+			crate::ip_policy::parse_networks(trusted_proxies,&mut trusted_v4,&mut trusted_v6)?;
+		}
+		Ok(Self{
+			client:client_rate.map(|rate|TokenBuckets::new(rate,client_burst)).transpose()?,
+			host:host_rate.map(|rate|TokenBuckets::new(rate,host_burst)).transpose()?,
+			trusted_v4,
+			trusted_v6,
+		})
+	}
+	fn is_trusted(&self,ip:IpAddr)->bool{
+		match ip{
+			IpAddr::V4(v4)=>self.trusted_v4.contains(&v4),
+			IpAddr::V6(v6)=>self.trusted_v6.contains(&v6),
+		}
+	}
+	//信頼するプロキシから受け取った場合のみ転送ヘッダを参照する
+	pub fn client_ip(&self,addr:SocketAddr,headers:&HeaderMap)->IpAddr{
+		let peer=addr.ip().to_canonical();
+		if !self.is_trusted(peer){
+			return peer;
+		}
+		let chain=forwarded_chain(headers);
+		//右から辿って最初の信頼しないアドレスがクライアント
+		for ip in chain.iter().rev(){
+			if !self.is_trusted(*ip){
+				return *ip;
+			}
+		}
+		chain.first().copied().unwrap_or(peer)
+	}
+	pub fn check_client(&self,ip:IpAddr)->Result<(),Duration>{
+		match &self.client{
+			Some(client)=>client.take(bucket_key(ip)),
+			None=>Ok(()),
+		}
+	}
+	pub fn check_host(&self,host:&str)->Result<(),Duration>{
+		match &self.host{
+			Some(limit)=>limit.take(host.to_owned()),
+			None=>Ok(()),
+		}
+	}
+}
+//IPv6は/64単位で数える
+fn bucket_key(ip:IpAddr)->IpAddr{
+	match ip{
+		IpAddr::V4(_)=>ip,
+		IpAddr::V6(v6)=>IpAddr::V6(Ipv6Addr::from(u128::from(v6)&!0xFFFF_FFFF_FFFF_FFFFu128)),
+	}
+}
+fn parse_node(s:&str)->Option<IpAddr>{
+	let s=s.trim().trim_matches('"');
+	if let Ok(ip)=s.parse::<IpAddr>(){
+		return Some(ip.to_canonical());
+	}
+	if let Ok(addr)=s.parse::<SocketAddr>(){
+		return Some(addr.ip().to_canonical());
+	}
+	s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok().map(|ip|ip.to_canonical())
+}
+fn forwarded_chain(headers:&HeaderMap)->Vec<IpAddr>{
+	let mut chain=vec![];
+	if headers.contains_key("Forwarded"){
+		for value in headers.get_all("Forwarded"){
+			let value=String::from_utf8_lossy(value.as_bytes());
+			for element in value.split(','){
+				for pair in element.split(';'){
+					if let Some((k,v))=pair.split_once('='){
+						if k.trim().eq_ignore_ascii_case("for"){
+							//unknownや_hiddenは無視する
+							if let Some(ip)=parse_node(v){
+								chain.push(ip);
+							}
+						}
+					}
+				}
+			}
+		}
+	}else{
+		for value in headers.get_all("X-Forwarded-For"){
+			let value=String::from_utf8_lossy(value.as_bytes());
+			for node in value.split(','){
+				if let Some(ip)=parse_node(node){
+					chain.push(ip);
+				}
+			}
+		}
+	}
+	chain
+}
+#[test]
+fn token_bucket(){
+	let limit=RateLimit::new(Some(1f64),Some(2),None,None,None).unwrap();
+	let ip:IpAddr="192.0.2.1".parse().unwrap();
+	assert!(limit.check_client(ip).is_ok());
+	assert!(limit.check_client(ip).is_ok());
+	let retry_after=limit.check_client(ip).unwrap_err();
+	assert!(retry_after<=Duration::from_secs(1));
+	assert!(limit.check_client("192.0.2.2".parse().unwrap()).is_ok());
+	//同じ/64は同じバケット
+	assert!(limit.check_client("2001:db8::1".parse().unwrap()).is_ok());
+	assert!(limit.check_client("2001:db8::2".parse().unwrap()).is_ok());
+	assert!(limit.check_client("2001:db8::3".parse().unwrap()).is_err());
+	assert!(limit.check_host("example.com").is_ok());
+}
+#[test]
+fn trusted_forwarding(){
+	let trusted=vec!["10.0.0.0/8".to_owned()];
+	let limit=RateLimit::new(None,None,None,None,Some(&trusted)).unwrap();
+	let mut headers=HeaderMap::new();
+	headers.append("X-Forwarded-For","198.51.100.7, 203.0.113.9, 10.0.0.3".parse().unwrap());
+	assert_eq!(limit.client_ip("10.0.0.2:1234".parse().unwrap(),&headers),"203.0.113.9".parse::<IpAddr>().unwrap());
+	//信頼しない接続元の転送ヘッダは無視する
+	assert_eq!(limit.client_ip("192.0.2.5:1234".parse().unwrap(),&headers),"192.0.2.5".parse::<IpAddr>().unwrap());
+	let mut headers=HeaderMap::new();
+	headers.append("Forwarded","for=\"[2001:db8::1]:4711\";proto=https, for=unknown".parse().unwrap());
+	assert_eq!(limit.client_ip("10.0.0.2:1234".parse().unwrap(),&headers),"2001:db8::1".parse::<IpAddr>().unwrap());
+}
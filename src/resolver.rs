@@ -0,0 +1,305 @@
+use std::{collections::HashMap, future::Future, net::{IpAddr, SocketAddr}, pin::Pin, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{host_policy::HostPolicy, ip_policy::IpPolicy};
+
+pub(crate) type LookupFuture<'a>=Pin<Box<dyn Future<Output=std::io::Result<Vec<IpAddr>>>+Send+'a>>;
+pub(crate) trait Lookup:Send+Sync{
+	fn lookup<'a>(&'a self,host:&'a str)->LookupFuture<'a>;
+}
+pub(crate) struct HickoryLookup{
+	resolver:hickory_resolver::TokioResolver,
+	timeout:Duration,
+}
+impl HickoryLookup{
+	pub fn new(
+		nameservers:Option<&Vec<String>>,
+		timeout:Duration,
+		positive_ttl:Option<Duration>,
+		negative_ttl:Option<Duration>,
+		ip_strategy:hickory_resolver::config::LookupIpStrategy,
+	)->Result<Self,String>{
+		use hickory_resolver::{config::{NameServerConfig, ResolverConfig}, name_server::TokioConnectionProvider, proto::xfer::Protocol, TokioResolver};
+		let mut builder=match nameservers{
+			Some(nameservers)=>{
+				let mut group=vec![];
+				for s in nameservers{
+					let s=s.trim();
+					//ポート番号が無い場合は53番を使う
+					let addr=match s.parse::<SocketAddr>(){
+						Ok(addr)=>addr,
+						Err(_)=>SocketAddr::new(s.parse::<IpAddr>().map_err(|_|format!("invalid nameserver: {}",s))?,53),
+					};
+					group.push(NameServerConfig::new(addr,Protocol::Udp));
+					group.push(NameServerConfig::new(addr,Protocol::Tcp));
+				}
+				TokioResolver::builder_with_config(ResolverConfig::from_parts(None,vec![],group),TokioConnectionProvider::default())
+			},
+			None=>TokioResolver::builder_tokio().map_err(|e|format!("{:?}",e))?,
+		};
+		let options=builder.options_mut();
+		options.timeout=timeout;
+		options.ip_strategy=ip_strategy;
+		options.cache_size=1024;
+		options.positive_max_ttl=positive_ttl;
+		if negative_ttl.is_some(){
+			options.negative_min_ttl=negative_ttl;
+			options.negative_max_ttl=negative_ttl;
+		}
+		Ok(Self{
+			resolver:builder.build(),
+			timeout,
+		})
+	}
+}
+impl Lookup for HickoryLookup{
+	fn lookup<'a>(&'a self,host:&'a str)->LookupFuture<'a>{
+		Box::pin(async move{
+			let ips=tokio::time::timeout(self.timeout,self.resolver.lookup_ip(host)).await;
+			let ips=ips.map_err(|_|std::io::Error::new(std::io::ErrorKind::TimedOut,"dns timeout"))?;
+			let ips=ips.map_err(std::io::Error::other)?;
+			Ok(ips.iter().collect())
+		})
+	}
+}
+//ホスト名ごとの許可したアドレスと許可した時刻
+type PinnedAddrs=HashMap<String,(Instant,Vec<IpAddr>)>;
+//check_urlで許可したアドレスをそのまま接続に使い、DNS rebindingを防ぐ
+#[derive(Clone)]
+pub(crate) struct PinnedResolver{
+	policy:Arc<IpPolicy>,
+	lookup:Arc<dyn Lookup>,
+	pinned:Arc<Mutex<PinnedAddrs>>,
+	pin_ttl:Duration,
+}
+impl PinnedResolver{
+	pub fn new(policy:Arc<IpPolicy>,lookup:Arc<dyn Lookup>,pin_ttl:Duration)->Self{
+		Self{
+			policy,
+			lookup,
+			pinned:Arc::new(Mutex::new(HashMap::new())),
+			pin_ttl,
+		}
+	}
+	pub async fn approve(&self,host:&str)->Result<Vec<IpAddr>,String>{
+		let host=host.trim_start_matches('[').trim_end_matches(']');
+		if let Ok(ip)=host.parse::<IpAddr>(){
+			self.policy.check(ip)?;
+			return Ok(vec![ip]);
+		}
+		let ips=self.lookup_checked(host).await?;
+		let now=Instant::now();
+		let mut pinned=self.pinned.lock().unwrap();
+		pinned.retain(|_,(time,_)|now.duration_since(*time)<self.pin_ttl);
+		pinned.insert(host.to_owned(),(now,ips.clone()));
+		Ok(ips)
+	}
+	async fn lookup_checked(&self,host:&str)->Result<Vec<IpAddr>,String>{
+		let ips=self.lookup.lookup(host).await.map_err(|e|format!("{:?} {}",e,host))?;
+		if ips.is_empty(){
+			return Err(format!("no address {}",host));
+		}
+		for ip in ips.iter(){
+			self.policy.check(*ip)?;
+		}
+		Ok(ips)
+	}
+	//リダイレクト先も同じポリシーで検査する
+	//IPアドレスのホストはresolverを通らないためここで検査し、ホスト名は接続時にresolverで検査する
+	pub fn redirect_policy(&self,host_policy:Arc<HostPolicy>,max_redirects:usize)->reqwest::redirect::Policy{
+		let policy=self.policy.clone();
+		reqwest::redirect::Policy::custom(move|attempt|{
+			if attempt.previous().len()>max_redirects{
+				return attempt.error(format!("RedirectLimit {}",max_redirects));
+			}
+			if let Err(e)=host_policy.check(attempt.url()){
+				return attempt.error(e);
+			}
+			let host=attempt.url().host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+			if let Ok(ip)=host.parse::<IpAddr>(){
+				if let Err(e)=policy.check(ip){
+					return attempt.error(e);
+				}
+			}
+			attempt.follow()
+		})
+	}
+	fn pinned(&self,host:&str)->Option<Vec<IpAddr>>{
+		let pinned=self.pinned.lock().unwrap();
+		pinned.get(host).filter(|(time,_)|time.elapsed()<self.pin_ttl).map(|(_,ips)|ips.clone())
+	}
+}
+impl reqwest::dns::Resolve for PinnedResolver{
+	fn resolve(&self,name:reqwest::dns::Name)->reqwest::dns::Resolving{
+		let resolver=self.clone();
+		let host=name.as_str().to_owned();
+		Box::pin(async move{
+			//リダイレクト先など事前に許可されていないホストはここで検査する
+			let ips=match resolver.pinned(&host){
+				Some(ips)=>ips,
+				None=>resolver.lookup_checked(&host).await?,
+			};
+			let addrs:reqwest::dns::Addrs=Box::new(ips.into_iter().map(|ip|SocketAddr::new(ip,0)));
+			Ok(addrs)
+		})
+	}
+}
+#[cfg(test)]
+struct FlipLookup(std::sync::atomic::AtomicUsize);
+#[cfg(test)]
+impl Lookup for FlipLookup{
+	fn lookup<'a>(&'a self,_host:&'a str)->LookupFuture<'a>{
+		//1回目は公開アドレス、2回目以降はループバックを返す
+		let n=self.0.fetch_add(1,std::sync::atomic::Ordering::SeqCst);
+		Box::pin(async move{
+			Ok(vec![if n==0{[93,184,216,34].into()}else{[127,0,0,1].into()}])
+		})
+	}
+}
+#[test]
+fn rebinding_pinned(){
+	use reqwest::dns::Resolve;
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let policy=Arc::new(IpPolicy::new(None,None).unwrap());
+	let resolver=PinnedResolver::new(policy,Arc::new(FlipLookup(0.into())),Duration::from_secs(10));
+	rt.block_on(async{
+		let approved=resolver.approve("rebind.example").await.unwrap();
+		assert_eq!(approved,vec![IpAddr::from([93,184,216,34])]);
+		let addrs:Vec<SocketAddr>=resolver.resolve("rebind.example".parse().unwrap()).await.unwrap().collect();
+		assert_eq!(addrs,vec![SocketAddr::new([93,184,216,34].into(),0)]);
+		//固定されていないホストは接続時に検査され、ループバックは拒否される
+		assert!(resolver.resolve("other.example".parse().unwrap()).await.is_err());
+		assert!(resolver.approve("rebind.example").await.is_err());
+	});
+}
+#[test]
+fn redirect_to_ip_literal(){
+	use std::io::{BufRead, BufReader, Write};
+	//パスが/next以外の場合は同じサーバの/nextへリダイレクトする
+	let listener=std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr=listener.local_addr().unwrap();
+	std::thread::spawn(move||{
+		for stream in listener.incoming(){
+			let mut stream=match stream{
+				Ok(stream)=>stream,
+				Err(_)=>break,
+			};
+			let mut line=String::new();
+			let mut reader=BufReader::new(&stream);
+			reader.read_line(&mut line).unwrap();
+			loop{
+				let mut header=String::new();
+				if reader.read_line(&mut header).unwrap()==0||header=="\r\n"{
+					break;
+				}
+			}
+			if line.starts_with("GET /next "){
+				write!(stream,"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").unwrap();
+			}else{
+				write!(stream,"HTTP/1.1 302 Found\r\nLocation: http://{}/next\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",addr).unwrap();
+			}
+		}
+	});
+	//同期的に検査するのでcurrent_threadのランタイムでも動く
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let host_policy=Arc::new(HostPolicy::new(None,None,None).unwrap());
+	let client=|allowed:Option<Vec<String>>|{
+		let policy=Arc::new(IpPolicy::new(allowed.as_ref(),None).unwrap());
+		let resolver=PinnedResolver::new(policy,Arc::new(FlipLookup(0.into())),Duration::from_secs(10));
+		reqwest::Client::builder().dns_resolver(Arc::new(resolver.clone())).redirect(resolver.redirect_policy(host_policy.clone(),10)).build().unwrap()
+	};
+	rt.block_on(async{
+		//最初の取得先はcheck_urlで検査済みとして、リダイレクト先のループバックは拒否される
+		let res=client(None).get(format!("http://{}/",addr)).send().await;
+		let e=res.unwrap_err();
+		assert!(e.is_redirect(),"{:?}",e);
+		assert!(format!("{:?}",e).contains("loopback"),"{:?}",e);
+		//許可されている場合は辿る
+		let res=client(Some(vec!["127.0.0.1/32".to_owned()])).get(format!("http://{}/",addr)).send().await.unwrap();
+		assert_eq!(res.text().await.unwrap(),"ok");
+	});
+}
+//A(93.184.216.34)を返し、missingで始まる名前にはNXDOMAINを返すスタブ
+//silentで始まる名前には応答しない
+#[cfg(test)]
+fn dns_stub()->(SocketAddr,Arc<std::sync::atomic::AtomicUsize>){
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	let socket=std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+	let addr=socket.local_addr().unwrap();
+	let queries=Arc::new(AtomicUsize::new(0));
+	let stub_queries=queries.clone();
+	std::thread::spawn(move||{
+		let mut buf=[0u8;512];
+		while let Ok((len,peer))=socket.recv_from(&mut buf){
+			stub_queries.fetch_add(1,Ordering::SeqCst);
+			let mut end=12;
+			while end<len&&buf[end]!=0{
+				end+=buf[end] as usize+1;
+			}
+			let question=&buf[12..end+5];
+			if question[1..].starts_with(b"silent"){
+				continue;
+			}
+			let nxdomain=question[1..].starts_with(b"missing");
+			let mut resp=vec![buf[0],buf[1],0x81,if nxdomain{0x83}else{0x80},0,1,0,if nxdomain{0}else{1},0,if nxdomain{1}else{0},0,0];
+			resp.extend_from_slice(question);
+			if nxdomain{
+				//ネガティブキャッシュにはSOAが必要
+				resp.extend_from_slice(&[0,0,6,0,1,0,0,0,60,0,22,0,0,0,0,0,1,0,0,0,60,0,0,0,60,0,0,0,60,0,0,0,60]);
+			}else{
+				resp.extend_from_slice(&[0xC0,0x0C,0,1,0,1,0,0,0,60,0,4,93,184,216,34]);
+			}
+			let _=socket.send_to(&resp,peer);
+		}
+	});
+	(addr,queries)
+}
+#[test]
+fn hickory_lookup_cache(){
+	use std::sync::atomic::Ordering;
+	let (addr,queries)=dns_stub();
+	let nameservers=vec![addr.to_string()];
+	let lookup=HickoryLookup::new(
+		Some(&nameservers),
+		Duration::from_secs(2),
+		None,
+		Some(Duration::from_secs(60)),
+		hickory_resolver::config::LookupIpStrategy::Ipv4Only,
+	).unwrap();
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	rt.block_on(async{
+		assert_eq!(lookup.lookup("cached.example").await.unwrap(),vec![IpAddr::from([93,184,216,34])]);
+		assert_eq!(lookup.lookup("cached.example").await.unwrap(),vec![IpAddr::from([93,184,216,34])]);
+		assert_eq!(queries.load(Ordering::SeqCst),1);
+		assert!(lookup.lookup("missing.example").await.is_err());
+		assert!(lookup.lookup("missing.example").await.is_err());
+		assert_eq!(queries.load(Ordering::SeqCst),2);
+	});
+}
+#[test]
+fn hickory_lookup_timeout_ttl(){
+	use std::sync::atomic::Ordering;
+	let (addr,queries)=dns_stub();
+	let nameservers=vec![addr.to_string()];
+	//応答のTTLは60秒だがdns_positive_ttlの1秒で打ち切る
+	let lookup=HickoryLookup::new(
+		Some(&nameservers),
+		Duration::from_millis(300),
+		Some(Duration::from_secs(1)),
+		None,
+		hickory_resolver::config::LookupIpStrategy::Ipv4Only,
+	).unwrap();
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	rt.block_on(async{
+		assert!(lookup.lookup("ttl.example").await.is_ok());
+		assert!(lookup.lookup("ttl.example").await.is_ok());
+		assert_eq!(queries.load(Ordering::SeqCst),1);
+		tokio::time::sleep(Duration::from_millis(1100)).await;
+		assert!(lookup.lookup("ttl.example").await.is_ok());
+		assert_eq!(queries.load(Ordering::SeqCst),2);
+		//応答が無い場合はdns_timeoutで諦める
+		let start=Instant::now();
+		let e=lookup.lookup("silent.example").await.unwrap_err();
+		assert_eq!(e.kind(),std::io::ErrorKind::TimedOut);
+		assert!(start.elapsed()<Duration::from_secs(1),"{:?}",start.elapsed());
+	});
+}
@@ -0,0 +1,48 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+//署名の対象はurl、変換モード(static,emoji,avatar,preview,badge)、有効期限
+pub fn message(url:&str,modes:&[&str],expires:Option<u64>)->String{
+	format!("{}\n{}\n{}",url,modes.join(","),expires.map(|e|e.to_string()).unwrap_or_default())
+}
+pub(crate) fn sign(secret:&[u8],url:&str,modes:&[&str],expires:Option<u64>)->String{
+	let mut mac=Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+	mac.update(message(url,modes,expires).as_bytes());
+	let mut sig=String::new();
+	for b in mac.finalize().into_bytes(){
+		sig.push_str(&format!("{:02x}",b));
+	}
+	sig
+}
+pub fn verify(secret:&[u8],url:&str,modes:&[&str],expires:Option<u64>,sig:Option<&str>,now:u64)->Result<(),String>{
+	let sig=sig.ok_or_else(||"Unsigned".to_owned())?.to_ascii_lowercase();
+	let expected=sign(secret,url,modes,expires);
+	//一致するまでの時間で推測されないように全体を比較する
+	let diff=sig.bytes().zip(expected.bytes()).fold(0u8,|acc,(a,b)|acc|(a^b));
+	if sig.len()!=expected.len()||diff!=0{
+		return Err("InvalidSignature".to_owned());
+	}
+	if let Some(expires)=expires{
+		if expires<now{
+			return Err(format!("SignatureExpired:{}",expires));
+		}
+	}
+	Ok(())
+}
+#[test]
+fn sign_verify(){
+	let secret=b"secret";
+	let url="https://example.com/a.png";
+	let sig=sign(secret,url,&["emoji"],None);
+	assert!(verify(secret,url,&["emoji"],None,Some(&sig),0).is_ok());
+	assert!(verify(secret,url,&["avatar"],None,Some(&sig),0).is_err());
+	assert!(verify(secret,"https://example.com/b.png",&["emoji"],None,Some(&sig),0).is_err());
+	assert!(verify(b"other",url,&["emoji"],None,Some(&sig),0).is_err());
+	assert!(verify(secret,url,&["emoji"],None,None,0).is_err());
+	assert!(verify(secret,url,&["emoji"],None,Some(&sig.to_ascii_uppercase()),0).is_ok());
+	assert!(verify(secret,url,&["emoji"],None,Some(&sig[..63]),0).is_err());
+	let sig=sign(secret,url,&[],Some(100));
+	assert!(verify(secret,url,&[],Some(100),Some(&sig),99).is_ok());
+	assert!(verify(secret,url,&[],Some(100),Some(&sig),101).is_err());
+	assert!(verify(secret,url,&[],Some(200),Some(&sig),99).is_err());
+}
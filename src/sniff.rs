@@ -0,0 +1,189 @@
+use image::ImageFormat;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum Detected{
+	Image(ImageFormat),
+	//image crate以外でデコードする形式
+	Jxl,
+	Jp2,
+	Jxr,
+	Svg,
+}
+impl Detected{
+	pub fn mime(&self)->&'static str{
+		match self{
+			Self::Image(format)=>format.to_mime_type(),
+			Self::Jxl=>"image/jxl",
+			Self::Jp2=>"image/jp2",
+			Self::Jxr=>"image/jxr",
+			Self::Svg=>"image/svg+xml",
+		}
+	}
+}
+struct Signature{
+	detected:Detected,
+	test:fn(&[u8])->bool,
+	//Noneは先頭のバイト列だけで判定できる形式
+	//Someはマジックナンバーが無い形式で、Content-Typeが一致する場合のみ採用する
+	content_types:Option<&'static [&'static str]>,
+}
+const SIGNATURES:&[Signature]=&[
+	Signature{detected:Detected::Image(ImageFormat::Png),test:|h|h.starts_with(b"\x89PNG\r\n\x1a\n"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Jpeg),test:|h|h.starts_with(&[0xFF,0xD8,0xFF]),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Gif),test:|h|h.starts_with(b"GIF87a")||h.starts_with(b"GIF89a"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::WebP),test:|h|h.starts_with(b"RIFF")&&h.get(8..12)==Some(b"WEBP"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Avif),test:is_avif,content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Tiff),test:|h|h.starts_with(b"II*\0")||h.starts_with(b"MM\0*"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Bmp),test:|h|h.starts_with(b"BM")&&h.len()>=26,content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Ico),test:is_ico,content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Qoi),test:|h|h.starts_with(b"qoif"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Farbfeld),test:|h|h.starts_with(b"farbfeld"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Hdr),test:|h|h.starts_with(b"#?RADIANCE")||h.starts_with(b"#?RGBE"),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::OpenExr),test:|h|h.starts_with(&[0x76,0x2F,0x31,0x01]),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Dds),test:|h|h.starts_with(b"DDS "),content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Pnm),test:is_pnm,content_types:None},
+	Signature{detected:Detected::Jxl,test:|h|h.starts_with(&[0xFF,0x0A])||h.starts_with(&[0x00,0x00,0x00,0x0C,0x4A,0x58,0x4C,0x20,0x0D,0x0A,0x87,0x0A]),content_types:None},
+	Signature{detected:Detected::Jp2,test:|h|h.starts_with(&[0xFF,0x4F,0xFF,0x51])||h.starts_with(&[0x00,0x00,0x00,0x0C,0x6A,0x50,0x20,0x20,0x0D,0x0A,0x87,0x0A]),content_types:None},
+	Signature{detected:Detected::Jxr,test:|h|h.starts_with(&[0x49,0x49,0xBC]),content_types:None},
+	Signature{detected:Detected::Svg,test:is_svg,content_types:None},
+	Signature{detected:Detected::Svg,test:is_svgz,content_types:None},
+	Signature{detected:Detected::Image(ImageFormat::Tga),test:is_tga,content_types:Some(&["image/x-targa","image/x-tga","image/tga"])},
+];
+//先頭のバイト列から形式を判定する
+//マジックナンバーで判定できた場合はContent-Typeより優先する
+pub(crate) fn detect(head:&[u8],content_type:Option<&str>)->Option<Detected>{
+	let content_type=content_type.map(essence);
+	for signature in SIGNATURES{
+		if let Some(content_types)=signature.content_types{
+			if !content_type.as_ref().map(|s|content_types.contains(&s.as_str())).unwrap_or(false){
+				continue;
+			}
+		}
+		if (signature.test)(head){
+			return Some(signature.detected);
+		}
+	}
+	None
+}
+//"image/svg+xml; charset=utf-8"のようなパラメータを除いて小文字にする
+pub(crate) fn essence(content_type:&str)->String{
+	content_type.split(';').next().unwrap_or_default().trim().to_lowercase()
+}
+pub(crate) fn is_gzip(head:&[u8])->bool{
+	head.starts_with(&[0x1F,0x8B,0x08])
+}
+fn is_avif(head:&[u8])->bool{
+	if head.get(4..8)!=Some(b"ftyp"){
+		return false;
+	}
+	let size=u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+	let ftyp=&head[..size.min(head.len())];
+	//major brandとcompatible brandsのどちらかにavifかavisがあればAVIF
+	//HEICのみのものは扱えない
+	let major=ftyp.get(8..12).into_iter();
+	let compatible=ftyp.get(16..).unwrap_or_default().chunks_exact(4);
+	major.chain(compatible).any(|brand|brand==b"avif"||brand==b"avis")
+}
+fn is_ico(head:&[u8])->bool{
+	//予約領域0、種類1(アイコン)、枚数1以上、1枚目の予約領域0
+	head.len()>=22&&head.starts_with(&[0,0,1,0])&&(head[4]!=0||head[5]!=0)&&head[9]==0
+}
+fn is_pnm(head:&[u8])->bool{
+	head.len()>=3&&head[0]==b'P'&&(b'1'..=b'7').contains(&head[1])&&head[2].is_ascii_whitespace()
+}
+fn is_svg(head:&[u8])->bool{
+	//途中で切れた文字は除く
+	let s=match std::str::from_utf8(head){
+		Ok(s)=>s,
+		Err(e)=>std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+	};
+	let mut s=s.strip_prefix('\u{feff}').unwrap_or(s);
+	//XML宣言、処理命令、コメント、DOCTYPEを読み飛ばして最初の要素がsvgか調べる
+	loop{
+		s=s.trim_start();
+		let end=if let Some(rest)=s.strip_prefix("<?"){
+			rest.find("?>").map(|i|s.len()-rest.len()+i+2)
+		}else if let Some(rest)=s.strip_prefix("<!--"){
+			rest.find("-->").map(|i|s.len()-rest.len()+i+3)
+		}else if s.get(..9).map(|p|p.eq_ignore_ascii_case("<!DOCTYPE")).unwrap_or(false){
+			doctype_end(s)
+		}else{
+			let rest=match s.strip_prefix("<svg"){
+				Some(rest)=>rest,
+				None=>return false,
+			};
+			//<svgfooのような別の要素は除く
+			return rest.starts_with(|c:char|c.is_ascii_whitespace()||c=='>'||c=='/'||c==':');
+		};
+		match end{
+			Some(end)=>s=&s[end..],
+			None=>return false,
+		}
+	}
+}
+//内部サブセット[...]の中の>では終わらない
+fn doctype_end(s:&str)->Option<usize>{
+	let mut in_subset=false;
+	let mut quote=None;
+	for (i,c) in s.char_indices(){
+		match (quote,c){
+			(Some(q),c) if q==c=>quote=None,
+			(Some(_),_)=>{},
+			(None,'"'|'\'')=>quote=Some(c),
+			(None,'[')=>in_subset=true,
+			(None,']')=>in_subset=false,
+			(None,'>') if !in_subset=>return Some(i+1),
+			_=>{},
+		}
+	}
+	None
+}
+fn is_svgz(head:&[u8])->bool{
+	use std::io::Read;
+	if !is_gzip(head){
+		return false;
+	}
+	//先頭部分だけ展開して判定する
+	let mut svg=Vec::new();
+	let _=flate2::read::GzDecoder::new(head).take(4096).read_to_end(&mut svg);
+	is_svg(&svg)
+}
+fn is_tga(head:&[u8])->bool{
+	if head.len()<18{
+		return false;
+	}
+	let color_map_type=head[1];
+	let image_type=head[2];
+	let width=u16::from_le_bytes([head[12],head[13]]);
+	let height=u16::from_le_bytes([head[14],head[15]]);
+	let depth=head[16];
+	let is_color_mapped=matches!(image_type,1|9);
+	matches!(color_map_type,0|1)&&
+	matches!(image_type,1|2|3|9|10|11)&&
+	(color_map_type==1)==is_color_mapped&&
+	matches!(depth,8|15|16|24|32)&&
+	width>0&&height>0
+}
+#[test]
+fn detect_formats(){
+	assert_eq!(detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",Some("image/svg+xml")),Some(Detected::Image(ImageFormat::Png)));
+	assert_eq!(detect(b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf",None),Some(Detected::Image(ImageFormat::Avif)));
+	assert_eq!(detect(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic",Some("image/heic")),None);
+	assert_eq!(detect(&[0xFF,0x0A,0xFA],None),Some(Detected::Jxl));
+	assert_eq!(detect(&[0x49,0x49,0xBC,0x01],None),Some(Detected::Jxr));
+	assert_eq!(detect("\u{feff}\n <svg xmlns=\"http://www.w3.org/2000/svg\">あ".as_bytes().split_last().unwrap().1,None),Some(Detected::Svg));
+	assert_eq!(detect(b"<?xml version=\"1.0\"?>\n<!-- c -->\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"x.dtd\" [<!ENTITY a \">\">]>\n<svg>",None),Some(Detected::Svg));
+	assert_eq!(detect(b"<?xml version=\"1.0\"?><svgx>",None),None);
+	assert_eq!(detect(b"<!-- <svg> ",None),None);
+	let mut svgz=flate2::write::GzEncoder::new(Vec::new(),flate2::Compression::default());
+	std::io::Write::write_all(&mut svgz,b"<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap();
+	let svgz=svgz.finish().unwrap();
+	//途中までしか無い場合も判定できる
+	assert_eq!(detect(&svgz[..svgz.len()-8],None),Some(Detected::Svg));
+	let tga=[0,0,2,0,0,0,0,0,0,0,0,0,16,0,16,0,32,8];
+	assert_eq!(detect(&tga,Some("image/x-tga")),Some(Detected::Image(ImageFormat::Tga)));
+	//マジックナンバーが無い形式はContent-Typeが一致する場合のみ
+	assert_eq!(detect(&tga,Some("image/png")),None);
+	assert_eq!(detect(b"<html>",Some("image/png")),None);
+	assert_eq!(Detected::Jp2.mime(),"image/jp2");
+}
@@ -0,0 +1,107 @@
+use std::{ops::Deref, path::PathBuf};
+
+use tokio::io::AsyncWriteExt;
+
+pub(crate) enum SrcBytes{
+	Memory(Vec<u8>),
+	//一時ファイルをmmapしたもの
+	Mapped(memmap2::Mmap),
+}
+impl Default for SrcBytes{
+	fn default()->Self{
+		Self::Memory(Vec::new())
+	}
+}
+impl Deref for SrcBytes{
+	type Target=[u8];
+	fn deref(&self)->&[u8]{
+		match self{
+			Self::Memory(v)=>v,
+			Self::Mapped(m)=>m,
+		}
+	}
+}
+impl AsRef<[u8]> for SrcBytes{
+	fn as_ref(&self)->&[u8]{
+		self
+	}
+}
+//しきい値まではメモリに持ち、超えたら一時ファイルへ書き出す
+pub(crate) struct SpillBuffer{
+	threshold:usize,
+	dir:Option<PathBuf>,
+	memory:Vec<u8>,
+	file:Option<tokio::fs::File>,
+	len:usize,
+}
+impl SpillBuffer{
+	pub fn new(threshold:usize,dir:Option<PathBuf>,len_hint:usize)->Self{
+		Self{
+			threshold,
+			dir,
+			memory:Vec::with_capacity(len_hint.min(threshold)),
+			file:None,
+			len:0,
+		}
+	}
+	pub fn len(&self)->usize{
+		self.len
+	}
+	pub fn is_spilled(&self)->bool{
+		self.file.is_some()
+	}
+	pub async fn push(&mut self,b:&[u8])->std::io::Result<()>{
+		if self.file.is_none()&&self.len+b.len()>self.threshold{
+			//名前の無い一時ファイルなので閉じると消える
+			let file=match &self.dir{
+				Some(dir)=>tempfile::tempfile_in(dir)?,
+				None=>tempfile::tempfile()?,
+			};
+			let mut file=tokio::fs::File::from_std(file);
+			file.write_all(&self.memory).await?;
+			self.memory=Vec::new();
+			self.file=Some(file);
+		}
+		match &mut self.file{
+			Some(file)=>file.write_all(b).await?,
+			None=>self.memory.extend_from_slice(b),
+		}
+		self.len+=b.len();
+		Ok(())
+	}
+	pub async fn finish(self)->std::io::Result<SrcBytes>{
+		let mut file=match self.file{
+			Some(file)=>file,
+			None=>return Ok(SrcBytes::Memory(self.memory)),
+		};
+		file.flush().await?;
+		let file=file.into_std().await;
+		if self.len==0{
+			return Ok(SrcBytes::default());
+		}
+		//他から参照されない一時ファイルなので書き換えられる事は無い
+		let map=unsafe{memmap2::Mmap::map(&file)?};
+		Ok(SrcBytes::Mapped(map))
+	}
+}
+#[test]
+fn spill_to_file(){
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	rt.block_on(async{
+		let mut buf=SpillBuffer::new(8,None,4);
+		buf.push(b"0123").await.unwrap();
+		assert!(!buf.is_spilled());
+		buf.push(b"456789").await.unwrap();
+		assert!(buf.is_spilled());
+		buf.push(b"abc").await.unwrap();
+		assert_eq!(buf.len(),13);
+		let bytes=buf.finish().await.unwrap();
+		assert!(matches!(bytes,SrcBytes::Mapped(_)));
+		assert_eq!(&*bytes,b"0123456789abc");
+		let mut buf=SpillBuffer::new(8,None,4);
+		buf.push(b"0123").await.unwrap();
+		let bytes=buf.finish().await.unwrap();
+		assert!(matches!(bytes,SrcBytes::Memory(_)));
+		assert_eq!(&*bytes,b"0123");
+	});
+}
@@ -1,41 +1,136 @@
 use std::sync::Arc;
 
+use axum::response::IntoResponse;
 use image::{DynamicImage, ImageBuffer};
 use resvg::usvg;
 
-use crate::RequestContext;
+use crate::{decode_limit::DecodeLimits, fonts::Fonts, spill::SrcBytes, RequestContext};
 
 impl RequestContext{
-	pub(crate) fn encode_svg(&self,fontdb:Arc<usvg::fontdb::Database>)->Result<DynamicImage,()>{
-		let mut options=usvg::Options{
-			fontdb:fontdb.clone(),
-			..Default::default()
+	//svgzは展開してから描画する
+	pub(crate) async fn decompress_svgz(&mut self)->Result<(),axum::response::Response>{
+		if !crate::sniff::is_gzip(&self.src_bytes){
+			return Ok(());
+		}
+		let svg=match gunzip(&self.src_bytes,self.config.max_size){
+			Ok(svg)=>svg,
+			Err(e)=>{
+				self.headers.append("X-Proxy-Error",e.parse().unwrap());
+				return Err((axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response());
+			}
+		};
+		let memory_budget=self.memory_budget.clone();
+		if let Err(e)=memory_budget.reserve(&mut self.reservation,svg.len() as u64).await{
+			return Err(self.memory_budget_error(e));
+		}
+		self.src_bytes=SrcBytes::Memory(svg);
+		Ok(())
+	}
+	//描画できない場合や絵文字をベクターのまま返す場合は無害化したSVGを返す
+	pub(crate) fn response_sanitized_svg(&mut self)->axum::response::Response{
+		let svg=match crate::svg_sanitize::sanitize(&self.src_bytes){
+			Ok(svg)=>svg,
+			Err(e)=>{
+				self.headers.append("X-Proxy-Error",e.parse().unwrap());
+				if self.parms.fallback.is_some(){
+					self.headers.remove("Content-Type");
+					self.headers.append("Content-Type","image/png".parse().unwrap());
+					return (axum::http::StatusCode::OK,self.headers.clone(),(*self.dummy_img).clone()).into_response();
+				}
+				return (axum::http::StatusCode::BAD_GATEWAY,self.headers.clone()).into_response();
+			}
 		};
-		for f in fontdb.faces(){
-			if let Some((name,_))=f.families.get(0){
-				//デフォルトフォントに存在する事が確実なフォントを使う
-				options.font_family=name.to_owned();
-				break;
+		self.headers.remove("Content-Length");
+		self.headers.remove("Content-Range");
+		self.headers.remove("Accept-Ranges");
+		self.headers.remove("Content-Type");
+		self.headers.append("Content-Type","image/svg+xml".parse().unwrap());
+		self.headers.append("Content-Security-Policy",crate::svg_sanitize::SVG_CSP.parse().unwrap());
+		Self::disposition_ext(&mut self.headers,".svg");
+		(axum::http::StatusCode::OK,self.headers.clone(),svg).into_response()
+	}
+	//描画は画像と同じ枠を使って別スレッドで行い、期限を過ぎたら諦める
+	//resvgの描画は途中で止められないため、期限後も描画が終わるまで枠とメモリの予約は保持される
+	pub(crate) async fn render_svg(mut self)->axum::response::Response{
+		let dummy_img=self.dummy_img.clone();
+		let is_fallback=self.parms.fallback.is_some();
+		let mut header=self.headers.clone();
+		let limits=SvgLimits::new(&self.config);
+		self.dpr=self.config.svg_device_pixel_ratio.filter(|dpr|dpr.is_finite()&&*dpr>0f32).unwrap_or(1f32);
+		let job_queue=self.job_queue.clone();
+		let resp=match job_queue.acquire().await{
+			Ok(permit)=>{
+				let deadline=match limits.render_timeout{
+					Some(timeout)=>self.deadline.min(tokio::time::Instant::now()+timeout),
+					None=>self.deadline,
+				};
+				let std_deadline=deadline.into_std();
+				let task=tokio::task::spawn_blocking(move||{
+					//処理が終わるまで枠を保持する
+					let _permit=permit;
+					let mut handle=self;
+					let fonts=handle.fonts.clone();
+					let img=handle.encode_svg(fonts,&limits,std_deadline);
+					let resp=img.map(|img|{
+						handle.headers.remove("Cache-Control");
+						handle.headers.append("Cache-Control","max-age=31536000, immutable".parse().unwrap());
+						handle.response_img(img)
+					});
+					(handle,resp)
+				});
+				match tokio::time::timeout_at(deadline,task).await{
+					Ok(Ok((_,Ok(resp))))=>resp,
+					Ok(Ok((mut handle,Err(SvgError::Parse))))=>return handle.response_sanitized_svg(),
+					Ok(Ok((mut handle,Err(SvgError::Budget(e)))))=>return handle.memory_budget_error(e),
+					Ok(Ok((_,Err(SvgError::Limit(e)))))=>{
+						header.append("X-Proxy-Error",e.parse().unwrap());
+						(axum::http::StatusCode::BAD_GATEWAY,header.clone()).into_response()
+					},
+					Ok(Ok((_,Err(SvgError::Timeout))))|Err(_)=>{
+						header.append("X-Proxy-Error","SvgRenderTimeout".parse().unwrap());
+						(axum::http::StatusCode::GATEWAY_TIMEOUT,header.clone()).into_response()
+					},
+					Ok(Err(_))=>{
+						header.append("X-Proxy-Error","SvgRenderThread".parse().unwrap());
+						(axum::http::StatusCode::INTERNAL_SERVER_ERROR,header.clone()).into_response()
+					},
+				}
+			},
+			Err(e)=>{
+				header.append("X-Proxy-Error",e.parse().unwrap());
+				header.append("Retry-After","1".parse().unwrap());
+				(axum::http::StatusCode::SERVICE_UNAVAILABLE,header.clone()).into_response()
 			}
+		};
+		if is_fallback&&resp.status()!=axum::http::StatusCode::OK{
+			header.remove("Content-Type");
+			header.remove("Retry-After");
+			header.append("Content-Type","image/png".parse().unwrap());
+			return (axum::http::StatusCode::OK,header,(*dummy_img).clone()).into_response();
 		}
+		resp
+	}
+	fn encode_svg(&mut self,fonts:Arc<Fonts>,limits:&SvgLimits,deadline:std::time::Instant)->Result<DynamicImage,SvgError>{
+		let options=options(&fonts,limits.decode.clone());
 		let tree=usvg::Tree::from_data(&self.src_bytes,&options);
 		let tree=match tree{
 			Ok(t)=>t,
-			Err(_)=>return Err(())
+			Err(_)=>return Err(SvgError::Parse)
 		};
 		let size=size(&tree);
 		let hint=self.image_size_hint();
 		
-		let (width,height,scale)=if size.width()>hint.0 as f32||size.height()>hint.1 as f32{
-			let scale = f32::min(hint.0 as f32 / size.width(), hint.1 as f32 / size.height());
-			let width=std::cmp::max((size.width() * scale).round() as u32,1);
-			let height=std::cmp::max((size.height() * scale).round() as u32,1);
-			(width,height,scale)
-		}else{
-			(size.width() as u32,size.height() as u32,1f32)
-		};
+		let (width,height,scale)=render_size(size,hint,self.has_size_mode());
+		limits.decode.check(width,height).map_err(SvgError::Limit)?;
+		limits.check_tree(&tree,scale).map_err(SvgError::Limit)?;
+		//描画先の画像の分、予約は描画が終わるまで保持する
+		let memory_budget=self.memory_budget.clone();
+		tokio::runtime::Handle::current().block_on(memory_budget.reserve(&mut self.reservation,width as u64*height as u64*4)).map_err(SvgError::Budget)?;
+		if std::time::Instant::now()>=deadline{
+			return Err(SvgError::Timeout);
+		}
 		let tf=usvg::Transform::from_scale(scale,scale);
-		let mut rgba=vec![0;(width*height*4) as usize];
+		let mut rgba=vec![0;width as usize*height as usize*4];
 		let mut pxmap=resvg::tiny_skia::PixmapMut::from_bytes(&mut rgba,width,height).unwrap();
 		resvg::render(&tree,tf,&mut pxmap);
 		match ImageBuffer::from_vec(width,height,rgba){
@@ -43,10 +138,110 @@ impl RequestContext{
 				Ok(DynamicImage::ImageRgba8(img))
 			},
 			None=>{
-				Err(())
+				Err(SvgError::Parse)
+			}
+		}
+	}
+}
+enum SvgError{
+	//usvgで読めないものは無害化して返す
+	Parse,
+	//制限を超えたものはエラーにする
+	Limit(String),
+	Budget(String),
+	Timeout,
+}
+//SVGの描画に使う資源の上限
+struct SvgLimits{
+	decode:DecodeLimits,
+	max_nodes:usize,
+	max_filter_pixels:u64,
+	render_timeout:Option<std::time::Duration>,
+}
+impl SvgLimits{
+	fn new(config:&crate::ConfigFile)->Self{
+		Self{
+			decode:DecodeLimits::new(config),
+			max_nodes:config.svg_max_nodes.unwrap_or(100_000),
+			max_filter_pixels:config.svg_max_filter_pixels.unwrap_or(4096*4096),
+			render_timeout:config.svg_render_timeout.map(std::time::Duration::from_millis),
+		}
+	}
+	//クリップパス、マスク、パターン等も含めて要素数とフィルタの範囲を調べる
+	fn check_tree(&self,tree:&usvg::Tree,scale:f32)->Result<(),String>{
+		let mut nodes=0;
+		visit_groups(tree.root(),&mut |group|{
+			nodes+=group.children().len();
+			if nodes>self.max_nodes{
+				return Err(format!("SvgLimit nodes:>{}",self.max_nodes));
 			}
+			let region=group.filters_bounding_box().and_then(|rect|rect.transform(group.abs_transform().post_scale(scale,scale)));
+			if let Some(region)=region{
+				let pixels=region.width() as u64*region.height() as u64;
+				if pixels>self.max_filter_pixels{
+					return Err(format!("SvgLimit filter:{}x{}>{}",region.width() as u64,region.height() as u64,self.max_filter_pixels));
+				}
+			}
+			Ok(())
+		})
+	}
+}
+fn options(fonts:&Arc<Fonts>,decode_limits:DecodeLimits)->usvg::Options<'static>{
+	usvg::Options{
+		fontdb:fonts.db(),
+		font_family:fonts.default_family().to_owned(),
+		font_resolver:fonts.font_resolver(),
+		//ファイルパスやURLは読まない
+		resources_dir:None,
+		image_href_resolver:usvg::ImageHrefResolver{
+			resolve_data:Box::new(move|mime,data,options|{
+				//埋め込まれたSVGは読まず、画像は展開後のサイズを確認してから使う
+				if mime=="image/svg+xml"||crate::sniff::is_gzip(&data){
+					return None;
+				}
+				let (width,height)=image::ImageReader::new(std::io::Cursor::new(&*data)).with_guessed_format().ok()?.into_dimensions().ok()?;
+				decode_limits.check(width,height).ok()?;
+				(usvg::ImageHrefResolver::default_data_resolver())(mime,data,options)
+			}),
+			resolve_string:Box::new(|_,_|None),
+		},
+		..Default::default()
+	}
+}
+fn visit_groups(group:&usvg::Group,f:&mut dyn FnMut(&usvg::Group)->Result<(),String>)->Result<(),String>{
+	f(group)?;
+	for node in group.children(){
+		if let usvg::Node::Group(g)=node{
+			visit_groups(g,f)?;
 		}
+		let mut result=Ok(());
+		node.subroots(|g|{
+			if result.is_ok(){
+				result=visit_groups(g,f);
+			}
+		});
+		result?;
+	}
+	Ok(())
+}
+//展開後のサイズもmax_sizeまでに制限する
+fn gunzip(src:&[u8],max_size:u64)->Result<Vec<u8>,String>{
+	use std::io::Read;
+	let mut svg=Vec::new();
+	flate2::read::GzDecoder::new(src).take(max_size+1).read_to_end(&mut svg).map_err(|e|format!("Svgz:{:?}",e))?;
+	if svg.len() as u64>max_size{
+		return Err(format!("SvgzLength:>{}",max_size));
 	}
+	Ok(svg)
+}
+//縦横比を保ったまま出力サイズで直接描画する
+//変換モードが無い場合は縮小のみ
+fn render_size(size:usvg::Size,hint:(u32,u32),upscale:bool)->(u32,u32,f32){
+	let scale=f32::min(hint.0 as f32/size.width(),hint.1 as f32/size.height());
+	let scale=if upscale{scale}else{scale.min(1f32)};
+	let width=std::cmp::max((size.width()*scale).round() as u32,1);
+	let height=std::cmp::max((size.height()*scale).round() as u32,1);
+	(width,height,scale)
 }
 fn size(tree:&usvg::Tree)->usvg::Size{
 	let bb=tree.root().bounding_box();
@@ -57,3 +252,46 @@ fn size(tree:&usvg::Tree)->usvg::Size{
 	}
 	tree.size()
 }
+#[test]
+fn gunzip_limit(){
+	use std::io::Write;
+	let mut svgz=flate2::write::GzEncoder::new(Vec::new(),flate2::Compression::default());
+	svgz.write_all(&[b' ';1000]).unwrap();
+	let svgz=svgz.finish().unwrap();
+	assert_eq!(gunzip(&svgz,1000).unwrap().len(),1000);
+	assert!(gunzip(&svgz,999).unwrap_err().starts_with("SvgzLength"));
+	assert!(gunzip(&svgz[..svgz.len()/2],1000).is_err());
+}
+#[test]
+fn svg_limits(){
+	let mut config=crate::test_config();
+	config.svg_max_nodes=Some(3);
+	config.svg_max_filter_pixels=Some(10000);
+	let limits=SvgLimits::new(&config);
+	let options=options(&Arc::new(Fonts::new(usvg::fontdb::Database::new(),&config)),limits.decode.clone());
+	let tree=|svg:&str|usvg::Tree::from_str(svg,&options).unwrap();
+	let head=r#"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="100">"#;
+	assert!(limits.check_tree(&tree(&format!(r#"{head}<rect width="1" height="1"/><rect width="1" height="1"/></svg>"#)),1.0).is_ok());
+	let many=r#"<rect width="1" height="1"/>"#.repeat(4);
+	assert!(limits.check_tree(&tree(&format!("{head}{many}</svg>")),1.0).unwrap_err().starts_with("SvgLimit nodes"));
+	//描画時の拡大率も含めてフィルタの範囲を調べる
+	let blur=format!(r#"{head}<filter id="f" x="0" y="0" width="1" height="1" filterUnits="userSpaceOnUse"><feGaussianBlur stdDeviation="1"/></filter><rect width="100" height="100" filter="url(#f)"/></svg>"#);
+	let blur=blur.replace(r#"width="1" height="1" filterUnits"#,r#"width="100" height="100" filterUnits"#);
+	assert!(limits.check_tree(&tree(&blur),1.0).is_ok());
+	assert!(limits.check_tree(&tree(&blur),2.0).unwrap_err().starts_with("SvgLimit filter"));
+	//ファイルパスや埋め込まれたSVGは読まない
+	let images=format!(r#"{head}<image href="/etc/passwd" width="1" height="1"/><image href="data:image/svg+xml;base64,PHN2Zy8+" width="1" height="1"/></svg>"#);
+	assert!(!tree(&images).root().has_children());
+}
+#[test]
+fn svg_render_size(){
+	let wh=|w,h|usvg::Size::from_wh(w,h).unwrap();
+	//16x16の絵文字は高さ128で描画する
+	assert_eq!(render_size(wh(16.0,16.0),(u32::MAX,128),true),(128,128,8.0));
+	assert_eq!(render_size(wh(16.0,16.0),(2048,2048),false),(16,16,1.0));
+	assert_eq!(render_size(wh(4000.0,1000.0),(2048,2048),false),(2048,512,0.512));
+	//viewBoxのみの場合もviewBoxの縦横比で描画する
+	let options=usvg::Options::default();
+	let tree=usvg::Tree::from_str(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 2 1"><rect width="2" height="1"/></svg>"#,&options).unwrap();
+	assert_eq!(render_size(size(&tree),(498,422),true),(498,249,249.0));
+}
@@ -0,0 +1,148 @@
+use std::{borrow::Cow, fmt::Write};
+
+const SVG_NS:&str="http://www.w3.org/2000/svg";
+const XLINK_NS:&str="http://www.w3.org/1999/xlink";
+const XML_NS:&str="http://www.w3.org/XML/1998/namespace";
+const NODES_LIMIT:u32=100_000;
+//再帰で書き出すので深すぎる入れ子は打ち切る
+const MAX_DEPTH:usize=256;
+
+//プロキシのURLを直接開かれてもスクリプトや外部の読み込みが動かないようにする
+pub(crate) const SVG_CSP:&str="default-src 'none'; img-src data:; style-src 'unsafe-inline'; sandbox";
+
+//script、foreignObject等はここに無いので出力されない
+const ALLOWED_ELEMENTS:&[&str]=&[
+	"a","animate","animateMotion","animateTransform","circle","clipPath","defs","desc","ellipse",
+	"feBlend","feColorMatrix","feComponentTransfer","feComposite","feConvolveMatrix","feDiffuseLighting",
+	"feDisplacementMap","feDistantLight","feDropShadow","feFlood","feFuncA","feFuncB","feFuncG","feFuncR",
+	"feGaussianBlur","feImage","feMerge","feMergeNode","feMorphology","feOffset","fePointLight",
+	"feSpecularLighting","feSpotLight","feTile","feTurbulence","filter","g","image","line","linearGradient",
+	"marker","mask","mpath","path","pattern","polygon","polyline","radialGradient","rect","set","stop",
+	"style","svg","switch","symbol","text","textPath","title","tspan","use","view",
+];
+//許可した要素と属性だけを書き出したSVGを作る
+pub(crate) fn sanitize(src:&[u8])->Result<Vec<u8>,String>{
+	let text=std::str::from_utf8(src).map_err(|_|"SvgSanitize:NotUtf8".to_owned())?;
+	let options=roxmltree::ParsingOptions{
+		allow_dtd:true,
+		nodes_limit:NODES_LIMIT,
+	};
+	let doc=roxmltree::Document::parse_with_options(text,options).map_err(|e|format!("SvgSanitize:{}",e.to_string().escape_default()))?;
+	let root=doc.root_element();
+	if root.tag_name().namespace()!=Some(SVG_NS)||root.tag_name().name()!="svg"{
+		return Err("SvgSanitize:NotSvg".to_owned());
+	}
+	let mut out=String::with_capacity(src.len());
+	write_element(&mut out,root,0);
+	Ok(out.into_bytes())
+}
+fn write_element(out:&mut String,node:roxmltree::Node,depth:usize){
+	let name=node.tag_name().name();
+	if depth>MAX_DEPTH||node.tag_name().namespace()!=Some(SVG_NS)||!ALLOWED_ELEMENTS.contains(&name){
+		return;
+	}
+	//リンク先やイベントハンドラを書き換えるアニメーションは除く
+	if matches!(name,"animate"|"set")&&node.attribute("attributeName").map(is_link_or_handler).unwrap_or(true){
+		return;
+	}
+	if name=="style"{
+		let css:String=node.children().filter(|c|c.is_text()).filter_map(|c|c.text()).collect();
+		if !is_safe_css(&css.to_ascii_lowercase()){
+			return;
+		}
+	}
+	out.push('<');
+	out.push_str(name);
+	if depth==0{
+		write!(out," xmlns=\"{}\" xmlns:xlink=\"{}\"",SVG_NS,XLINK_NS).unwrap();
+	}
+	for attr in node.attributes(){
+		let qname:Cow<str>=match attr.namespace(){
+			None=>attr.name().into(),
+			Some(XLINK_NS)=>format!("xlink:{}",attr.name()).into(),
+			Some(XML_NS)=>format!("xml:{}",attr.name()).into(),
+			_=>continue,
+		};
+		if is_safe_attribute(name,&qname,attr.value()){
+			write!(out," {}=\"{}\"",qname,escape(attr.value())).unwrap();
+		}
+	}
+	let mut children=String::new();
+	for child in node.children(){
+		if child.is_element(){
+			write_element(&mut children,child,depth+1);
+		}else if let Some(text)=child.text().filter(|_|child.is_text()){
+			children.push_str(&escape(text));
+		}
+	}
+	if children.is_empty(){
+		out.push_str("/>");
+	}else{
+		write!(out,">{}</{}>",children,name).unwrap();
+	}
+}
+fn is_link_or_handler(name:&str)->bool{
+	let name=name.trim().to_ascii_lowercase();
+	name.starts_with("on")||name=="href"||name=="xlink:href"
+}
+fn is_safe_attribute(element:&str,qname:&str,value:&str)->bool{
+	let value=value.trim().to_ascii_lowercase();
+	if qname.to_ascii_lowercase().starts_with("on"){
+		return false;
+	}
+	if qname=="href"||qname=="xlink:href"{
+		//文書内の参照と画像のdata URLのみ
+		return value.starts_with('#')||(matches!(element,"image"|"feImage")&&is_data_image(&value));
+	}
+	is_safe_css(&value)
+}
+fn is_data_image(value:&str)->bool{
+	["data:image/png","data:image/jpeg","data:image/gif","data:image/webp"].iter().any(|p|value.starts_with(p))
+}
+//url()は文書内の参照のみ許可する
+fn is_safe_css(value:&str)->bool{
+	//エスケープで判定を回避されないように\を含むものは除く
+	if value.contains('\\')||value.contains("@import")||value.contains("expression(")||value.contains("javascript:"){
+		return false;
+	}
+	//url()以外で外部を参照できる関数や引用符で囲まれたURLも除く
+	if ["image-set(","image(","cross-fade(","src(","//"].iter().any(|p|value.contains(p)){
+		return false;
+	}
+	value.split("url(").skip(1).all(|rest|rest.trim_start().trim_start_matches(['"','\'']).starts_with('#'))
+}
+fn escape(s:&str)->Cow<'_,str>{
+	if !s.contains(['&','<','>','"']){
+		return s.into();
+	}
+	s.replace('&',"&amp;").replace('<',"&lt;").replace('>',"&gt;").replace('"',"&quot;").into()
+}
+#[test]
+fn sanitize_svg(){
+	let src=br##"<?xml version="1.0"?>
+<!DOCTYPE svg [<!ENTITY c "red">]>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:i="http://ns.example/" width="10" height="10" onload="alert(1)" i:x="1">
+	<script>alert(1)</script>
+	<style>@import url(https://evil.example/a.css);</style>
+	<style>rect{fill:url(#g)}</style>
+	<style>rect{background:image-set("https://evil.example/a.png" 1x)}</style>
+	<style>@font-face{src:local(a)}rect{cursor:"https://evil.example/c.cur"}</style>
+	<foreignObject><div xmlns="http://www.w3.org/1999/xhtml">x</div></foreignObject>
+	<a xlink:href="javascript:alert(1)"><rect width="5" height="5" fill="&c;" style="fill:url(https://evil.example/)"/></a>
+	<use href="#g"/>
+	<image href="https://evil.example/a.png"/>
+	<image xlink:href="data:image/png;base64,AAAA"/>
+	<set attributeName="href" to="javascript:alert(1)"/>
+	<text>a&lt;b</text>
+</svg>"##;
+	let out=String::from_utf8(sanitize(src).unwrap()).unwrap();
+	for bad in ["script","onload","evil.example","foreignObject","javascript","i:x","<set"]{
+		assert!(!out.contains(bad),"{} in {}",bad,out);
+	}
+	for good in ["rect{fill:url(#g)}","fill=\"red\"","<use href=\"#g\"/>","xlink:href=\"data:image/png;base64,AAAA\"","<text>a&lt;b</text>"]{
+		assert!(out.contains(good),"{} not in {}",good,out);
+	}
+	//出力もSVGとして読める
+	assert!(resvg::usvg::Tree::from_data(out.as_bytes(),&Default::default()).is_ok());
+	assert!(sanitize(b"<html/>").is_err());
+}